@@ -0,0 +1,87 @@
+use crate::cards::{self, Error};
+use crate::registry::{self, CardKind};
+
+// Fetching many cards in one request, either of one type (GET /<type>?ids=1,2,3) or of any
+// mix of types (POST /cards/batch with a JSON array of qualified IDs). The result is a JSON
+// object keyed by qualified ID with the contents of every card. Cards that don't exist (or
+// are of a type we don't know) come back as null rather than failing the whole batch. Cards
+// whose files can't be read or aren't valid JSON come back as {"error": "<what's wrong>"}.
+
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+// The comma-separated list of IDs given to GET /<type>?ids=...
+pub fn parse_ids(ids: &str) -> Result<Vec<u64>, Error> {
+    let ids = ids.split(',')
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<u64>().map_err(|_| Error::InvalidQuery(format!("'{}' is not a card ID", id))))
+        .collect::<Result<Vec<u64>, Error>>()?;
+    check_size(ids.len())?;
+    Ok(ids)
+}
+
+// The body of POST /cards/batch.
+pub fn parse_qualified_ids(body: &serde_json::Value) -> Result<Vec<String>, Error> {
+    let ids = body.as_array()
+        .ok_or_else(|| Error::InvalidQuery(String::from("Expected a JSON array of qualified card IDs")))?
+        .iter()
+        .map(|id| match id.as_str() {
+            Some(id) if cards::parse_qualified_id(id).is_ok() => Ok(String::from(id)),
+            _ => Err(Error::InvalidQuery(format!("{} is not a qualified card ID (type/id)", id))),
+        })
+        .collect::<Result<Vec<String>, Error>>()?;
+    check_size(ids.len())?;
+    Ok(ids)
+}
+
+fn check_size(count: usize) -> Result<(), Error> {
+    if !(1..=MAX_BATCH_SIZE).contains(&count) {
+        return Err(Error::InvalidQuery(format!("Between 1 and {} cards can be fetched at once", MAX_BATCH_SIZE)));
+    }
+    Ok(())
+}
+
+// Contents of the given card or None if there's no such card.
+fn read_card(kind: &dyn CardKind, id: u64, db: &rusqlite::Connection) -> Result<Option<serde_json::Value>, Error> {
+    let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let json = cards::read_card_json(&path)?;
+    serde_json::from_str(&json)
+        .map(Some)
+        .map_err(|err| Error::CantReadFormatOfCard(path, err))
+}
+
+// What goes into the result for the given card. Only problems with the index fail the batch.
+fn card_entry(card: Result<Option<serde_json::Value>, Error>) -> Result<serde_json::Value, Error> {
+    match card {
+        Ok(card) => Ok(card.unwrap_or(serde_json::Value::Null)),
+        Err(e) if crate::quarantine::is_card_error(&e) => Ok(serde_json::json!({ "error": e.reason() })),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn read_cards(kind: &dyn CardKind, ids: &[u64], db: &rusqlite::Connection) -> Result<serde_json::Value, Error> {
+    let mut result = serde_json::Map::new();
+    for id in ids.iter() {
+        let card = card_entry(read_card(kind, *id, db))?;
+        result.insert(format!("{}/{}", kind.typ_str(), id), card);
+    }
+    Ok(serde_json::Value::Object(result))
+}
+
+// Same as read_cards() but for cards of any type. The result is keyed by the qualified IDs
+// as given.
+pub fn read_qualified_cards(qualified_ids: &[String], db: &rusqlite::Connection) -> Result<serde_json::Value, Error> {
+    let mut result = serde_json::Map::new();
+    for qualified_id in qualified_ids.iter() {
+        let (code, id) = cards::parse_qualified_id(qualified_id)?;
+        let card = match registry::get().find_by_code(code) {
+            Some(kind) => card_entry(read_card(kind, id, db))?,
+            None => serde_json::Value::Null,
+        };
+        result.insert(qualified_id.clone(), card);
+    }
+    Ok(serde_json::Value::Object(result))
+}
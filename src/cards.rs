@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
 use rusqlite::{OptionalExtension, params};
 use urlencoding::decode;
 
@@ -70,25 +72,144 @@ impl FromStr for CardType {
 #[derive(std::fmt::Debug)]
 pub enum Error {
     CantFindCard(String),
-    CantAccessCard,
-    CantReadFormatOfCard,
-    CantReadProperty(String),
-    DatabaseError(String),
+    CantAccessCard(PathBuf, std::io::Error),
+    CantReadCardFolder(PathBuf, std::io::Error),
+    CantReadFormatOfCard(PathBuf, serde_json::Error),
+    CantReadProperty(PropertyError),
+    CantWriteExport(std::io::Error),
+    DatabaseError(rusqlite::Error),
+    InvalidSchema(String),
+    InvalidQuery(String),
+    InvalidCard(String),
+    PreconditionFailed(String),
+    CardExists(String),
+    DatabaseUnavailable(String),
+}
+
+// A property of a card that is missing or doesn't have the type we need. The property
+// getters don't know which card they are looking at; the card gets filled in by whoever
+// loads the card (see Error::in_card()).
+#[derive(std::fmt::Debug)]
+pub struct PropertyError {
+    pub property: String,
+    pub expected: &'static str,
+    pub found: &'static str,
+    pub card: Option<(String, u64, PathBuf)>, // Type, ID and file
+}
+
+impl PropertyError {
+    pub fn new(property: &str, expected: &'static str, found: Option<&serde_json::Value>) -> PropertyError {
+        PropertyError {
+            property: String::from(property),
+            expected,
+            found: found.map(json_type_name).unwrap_or("missing"),
+            card: None,
+        }
+    }
+}
+
+impl std::fmt::Display for PropertyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} should be {} but is {}", self.property, self.expected, self.found)
+    }
+}
+
+impl Error {
+    // Tell property errors which card they are about.
+    pub fn in_card(self, typ: &str, id: u64, path: &Path) -> Error {
+        match self {
+            Error::CantReadProperty(mut e) => {
+                e.card.get_or_insert_with(|| (String::from(typ), id, path.to_path_buf()));
+                Error::CantReadProperty(e)
+            },
+            e => e,
+        }
+    }
+
+    // What went wrong without saying where, for when the card is known from elsewhere.
+    pub fn reason(&self) -> String {
+        match self {
+            Error::CantAccessCard(_, e) => e.to_string(),
+            Error::CantReadFormatOfCard(_, e) => e.to_string(),
+            Error::CantReadProperty(e) => e.to_string(),
+            Error::InvalidCard(e) => e.clone(),
+            e => e.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::CantFindCard(card) => write!(f, "cannot find card {}", card),
+            Error::CantAccessCard(path, e) => write!(f, "cannot access {}: {}", path.display(), e),
+            Error::CantReadCardFolder(path, e) => write!(f, "cannot read files in {}: {}", path.display(), e),
+            // serde_json errors come with line and column.
+            Error::CantReadFormatOfCard(path, e) => write!(f, "{} is not a valid card: {}", path.display(), e),
+            Error::CantReadProperty(e) => match &e.card {
+                Some((typ, id, path)) => write!(f, "{}/{} ({}): {}", typ, id, path.display(), e),
+                None => write!(f, "{}", e),
+            },
+            Error::CantWriteExport(e) => write!(f, "cannot write export: {}", e),
+            Error::DatabaseError(e) => write!(f, "database error: {}", e),
+            Error::InvalidSchema(e) => write!(f, "invalid schema: {}", e),
+            Error::InvalidQuery(e) => write!(f, "invalid query: {}", e),
+            Error::InvalidCard(e) => write!(f, "invalid card: {}", e),
+            Error::PreconditionFailed(e) => write!(f, "precondition failed: {}", e),
+            Error::CardExists(e) => write!(f, "{}", e),
+            Error::DatabaseUnavailable(e) => write!(f, "database unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CantAccessCard(_, e) | Error::CantReadCardFolder(_, e) | Error::CantWriteExport(e) => Some(e),
+            Error::CantReadFormatOfCard(_, e) => Some(e),
+            Error::DatabaseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
 }
 
 pub fn get_path_to_cards() -> PathBuf {
-    ////TODO: Make this configurable.
-    PathBuf::from("C:/Dropbox/Data/Cards")
+    crate::config::get().cards_path.clone()
 }
 
-pub fn parse_qualified_id(qualified_id: &str) -> Result<(CardType, u64), Error> {
-    let slash = qualified_id.find('/').ok_or(Error::DatabaseError(String::from("card link is missing /")))?;
-    let typ = CardType::from_str(&qualified_id[..slash]).map_err(|_| Error::DatabaseError(String::from("invalid card type")))?;
-    let id = qualified_id[(slash + 1)..].parse::<u64>().map_err(|_| Error::DatabaseError(String::from("invalid card ID")))?;
+pub fn parse_qualified_id(qualified_id: &str) -> Result<(u32, u64), Error> {
+    let invalid = || Error::InvalidQuery(format!("'{}' is not a qualified card ID (type/id)", qualified_id));
+    let slash = qualified_id.find('/').ok_or_else(invalid)?;
+    let typ = get_type_code(&qualified_id[..slash]);
+    let id = qualified_id[(slash + 1)..].parse::<u64>().map_err(|_| invalid())?;
 
     Ok((typ, id))
 }
 
+// Numeric code of the given card type as stored in the Links and Taggings tables. Types
+// defined by schema files bring their own code. Unknown types map to CardType::Invalid.
+pub fn get_type_code(typ: &str) -> u32 {
+    match CardType::from_str(typ) {
+        Ok(CardType::Invalid) | Err(_) => crate::registry::get().find(typ)
+            .map(|kind| kind.code())
+            .unwrap_or(CardType::Invalid as u32),
+        Ok(typ) => typ as u32,
+    }
+}
+
+// Where a new card goes. Existing cards may also live in sub-directories of their type
+// folder; where exactly is recorded in CardFiles.
 fn get_file_path_for_card(typ: &str, id: u64) -> PathBuf {
     let mut path = get_path_to_cards();
     path.push(typ);
@@ -96,71 +217,125 @@ fn get_file_path_for_card(typ: &str, id: u64) -> PathBuf {
     path
 }
 
+// 64-bit FNV-1a. Only used to detect changes to card files so doesn't need to be
+// cryptographic but needs to be stable across builds (unlike DefaultHasher).
+pub fn hash_contents(contents: &[u8]) -> i64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in contents {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash as i64
+}
+
+// ETags are derived from the contents of the card file so that they change whenever the
+// file does, regardless of whether it was changed through the API or on disk.
+pub fn get_card_etag(contents: &[u8]) -> String {
+    format!("\"{:016x}\"", hash_contents(contents) as u64)
+}
+
+// Apply a JSON merge patch (RFC 7396) to `target`. Null values remove properties, objects
+// are merged recursively and anything else replaces what was there.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    match patch {
+        serde_json::Value::Object(patch) => {
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (name, value) in patch.iter() {
+                if value.is_null() {
+                    target.remove(name);
+                }
+                else {
+                    merge_patch(target.entry(name.clone()).or_insert(serde_json::Value::Null), value);
+                }
+            }
+        },
+        _ => *target = patch.clone(),
+    }
+}
+
+// What the property getters expect to find for the given Rust type.
+fn expected_json_type<T>() -> &'static str {
+    match std::any::type_name::<T>() {
+        "bool" => "a boolean",
+        "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" => "an integer",
+        "f32" | "f64" => "a number",
+        _ => "a string",
+    }
+}
+
 fn get_property<T: FromStr + Default>(json: &serde_json::Value, name: &str) -> Result<T, Error> {
-    ////FIXME: this is horrible code...
+    let err = || Error::CantReadProperty(PropertyError::new(name, expected_json_type::<T>(), json.get(name)));
     let str = match json.get(name) {
+        None => String::new(),
         Some(serde_json::Value::Null) => String::from("null"),
-        Some(serde_json::Value::Bool(b)) => if *b { String::from("true") } else { String::from("false") },
+        Some(serde_json::Value::Bool(b)) => b.to_string(),
         Some(serde_json::Value::String(s)) => s.to_string(),
-        Some(serde_json::Value::Number(n)) =>
-            if n.is_f64() { n.as_f64().unwrap().to_string() }
-            else if n.is_i64() { n.as_i64().unwrap().to_string() }
-            else { n.as_u64().unwrap().to_string() }
-        ////TODO: should raise Error::CantReadProperty
-        _ => String::new()
+        Some(serde_json::Value::Number(n)) => n.to_string(),
+        Some(_) => return Err(err()),
     };
     if str.is_empty() {
         Ok(T::default())
     }
     else {
-        let val = str.parse::<T>().map_err(|_| Error::CantReadProperty(String::from(name)))?;
-        Ok(val)
+        str.parse::<T>().map_err(|_| err())
     }
 }
 
 fn get_optional_property<T: FromStr>(json: &serde_json::Value, name: &str) -> Result<Option<T>, Error> {
-    if let Some(ref val) = json.get(name) {
-        match val {
-            serde_json::Value::Null => Ok(None),
-            serde_json::Value::String(str) => str.parse::<T>().map_err(|_| Error::CantReadProperty(String::from(name))).map(|v| Some(v)),
-            ////REVIEW: There's probably better ways to turn a serde::Value into a string but haven't found one so far (as_str only works for actual strings).
-            _ => format!("{}", val).parse::<T>().map_err(|_| Error::CantReadProperty(String::from(name))).map(|v| Some(v)),
-        }
-    }
-    else
-    {
-        Ok(None)
+    let err = || Error::CantReadProperty(PropertyError::new(name, expected_json_type::<T>(), json.get(name)));
+    match json.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(str)) => str.parse::<T>().map(Some).map_err(|_| err()),
+        Some(serde_json::Value::Array(_)) | Some(serde_json::Value::Object(_)) => Err(err()),
+        Some(val) => val.to_string().parse::<T>().map(Some).map_err(|_| err()),
     }
 }
 
 fn get_bool_property(json: &serde_json::Value, name: &str) -> Result<bool, Error> {
+    let err = || Error::CantReadProperty(PropertyError::new(name, "a boolean", json.get(name)));
     match &json[name] {
         serde_json::Value::Null => Ok(false),
         serde_json::Value::Bool(b) => Ok(*b),
-        serde_json::Value::String(s) => s.parse::<bool>().map_err(|_| Error::CantReadProperty(String::from(name))),
-        _ => Err(Error::CantReadProperty(String::from(name)))
+        serde_json::Value::String(s) => s.parse::<bool>().map_err(|_| err()),
+        _ => Err(err())
     }
 }
 
 fn get_string_list_property(json: &serde_json::Value, name: &str) -> Result<Vec<String>, Error> {
+    let err = || Error::CantReadProperty(PropertyError::new(name, "an array of strings", json.get(name)));
     match &json[name] {
         serde_json::Value::Null => Ok(Vec::new()),
         serde_json::Value::Array(vec) => {
             let mut r = Vec::new();
             for v in vec.iter() {
-                r.push(String::from(v.as_str().ok_or(Error::CantReadProperty(String::from(name)))?))
+                r.push(String::from(v.as_str().ok_or_else(err)?))
             }
             Ok(r)
         }
-        _ => Err(Error::CantReadProperty(String::from(name)))
+        _ => Err(err())
     }
 }
 
-fn load_card_from_json<T: Card, F: FnOnce(CardData) -> Result<T, Error>>(id: u64, f: F) -> Result<T, Error> {
-    let path = get_file_path_for_card(T::typ_str(), id);
-    let contents = fs::read_to_string(path).map_err(|_| Error::CantAccessCard)?;
-    let json: serde_json::Value = serde_json::from_str(&contents).map_err(|_| Error::CantReadFormatOfCard)?;
+fn load_card_from_json<T: Card, F: FnOnce(CardData) -> Result<T, Error>>(path: &Path, id: u64, f: F) -> Result<T, Error> {
+    load_card_data(T::typ_str(), path, id)
+        .and_then(f)
+        .map_err(|e| e.in_card(T::typ_str(), id, path))
+}
 
+pub fn load_card_data(typ: &str, path: &Path, id: u64) -> Result<CardData, Error> {
+    let contents = fs::read_to_string(path).map_err(|err| Error::CantAccessCard(path.to_path_buf(), err))?;
+    let json: serde_json::Value = serde_json::from_str(&contents).map_err(|err| Error::CantReadFormatOfCard(path.to_path_buf(), err))?;
+
+    let data = load_common_fields(id, json)
+        .map_err(|e| e.in_card(typ, id, path))?;
+
+    Ok(data)
+}
+
+fn load_common_fields(id: u64, json: serde_json::Value) -> Result<CardData, Error> {
     let data = CardData {
         id,
         title: get_property(&json, "Title")?,
@@ -172,18 +347,18 @@ fn load_card_from_json<T: Card, F: FnOnce(CardData) -> Result<T, Error>>(id: u64
         contents: json,
     };
 
-    f(data)
+    Ok(data)
 }
 
-struct CardData {
-    id: u64,
-    title: String,
-    created: String,
-    modified: String,
-    source: Option<String>,
-    tags: Vec<String>,
-    links: Vec<String>,
-    contents: serde_json::Value,
+pub struct CardData {
+    pub id: u64,
+    pub title: String,
+    pub created: String,
+    pub modified: String,
+    pub source: Option<String>,
+    pub tags: Vec<String>,
+    pub links: Vec<String>,
+    pub contents: serde_json::Value,
 }
 
 pub trait Card
@@ -199,9 +374,8 @@ pub trait Card
 
     fn typ() -> CardType;
     fn typ_str() -> &'static str;
-    fn load(id: u64) -> Result<Self, Error>;
+    fn load(path: &Path, id: u64) -> Result<Self, Error>;
 
-    fn sql_schema() ->&'static str;
     fn sql_table() -> &'static str;
     fn sql_write_stmt() -> &'static str;
     fn sql_write(&self, stmt: &mut rusqlite::Statement) -> Result<usize, Error>;
@@ -210,190 +384,542 @@ pub trait Card
         format!("{}/{}", Self::typ_str(), self.id())
     }
 
-    fn path() -> PathBuf {
-        let mut path = PathBuf::new();
-        path.push(get_path_to_cards());
-        path.push(Self::typ_str());
-        path
-    }
-
-    fn list() -> Vec<u64> {
-
-        let path = Self::path();
-        let mut result = Vec::new();
-
-        for entry in path.read_dir().expect(format!("Can read files in {}/ directory", Self::typ_str()).as_str()) {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if let Some(extension) = path.extension() {
-                    if extension != "json" {
-                        continue
-                    }
-                    if let Some(stem) = path.file_stem() {
-                        if let Ok(id) = stem.to_str().unwrap().parse::<u64>() {
-                            if let Ok(_) = entry.file_type() {
-                                result.push(id)
-                            }
-                        }
-                    }
+    fn sql_write_links(&self, db: &mut rusqlite::Statement) -> Result<(), Error> {
+        sql_write_card_links(Self::typ() as u32, self.id(), self.links(), db)
+    }
+
+    fn sql_write_tags(&self, tag_insert: &mut rusqlite::Statement, tag_lookup: &mut rusqlite::Statement, tagging_insert: &mut rusqlite::Statement) -> Result<(), Error> {
+        sql_write_card_tags(Self::typ() as u32, self.id(), self.tags(), tag_insert, tag_lookup, tagging_insert)
+    }
+}
+
+// The following are shared between the Card implementations here and card types defined
+// by schema files (see schemas.rs and registry.rs). They only depend on the type name,
+// numeric type code and table name of a card type.
+
+pub fn prepare_card_write_stmts<'a>(db: &'a rusqlite::Connection, write_stmt: &str)
+    -> Result<(rusqlite::Statement<'a>, rusqlite::Statement<'a>, rusqlite::Statement<'a>, rusqlite::Statement<'a>, rusqlite::Statement<'a>), rusqlite::Error> {
+    Ok((db.prepare(write_stmt)?,
+        db.prepare("INSERT INTO Links (role, from_type, from_id, to_type, to_id, dangling) VALUES(?1, ?2, ?3, ?4, ?5, NOT EXISTS (SELECT 1 FROM CardFiles WHERE card_type IS ?4 AND card_id IS ?5))")?,
+        db.prepare("INSERT OR IGNORE INTO Tags VALUES(?1)")?,
+        db.prepare("SELECT rowid FROM Tags WHERE name IS ?1")?,
+        db.prepare("INSERT INTO Taggings (tag_id, card_type, card_id) VALUES(?1, ?2, ?3)")?))
+}
+
+pub fn get_path_to_card_type(typ: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(get_path_to_cards());
+    path.push(typ);
+    path
+}
+
+// Files that have been reported as ignored. Type folders are rescanned periodically and
+// the same files would otherwise be reported every time. Files drop out of here once they
+// are gone (or no longer ignored) so that they are reported again if they come back.
+static IGNORED_FILES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+// All card files of the given type as (id, path). Type folders may be split up into
+// sub-directories to any depth (e.g. metric/2022/04/123.json). If the same ID turns up
+// more than once, the first file found wins.
+//
+// Fails if any folder can't be read. A partial list would look like cards had been deleted.
+pub fn list_card_files(typ: &str) -> Result<Vec<(u64, PathBuf)>, Error> {
+
+    fn visit(typ: &str, dir: &Path, seen: &mut HashMap<u64, PathBuf>, result: &mut Vec<(u64, PathBuf)>, ignored: &mut Vec<(PathBuf, String)>) -> Result<(), Error> {
+        let entries = dir.read_dir()
+            .map_err(|err| Error::CantReadCardFolder(dir.to_path_buf(), err))?;
+        for entry in entries {
+            let entry = entry.map_err(|err| Error::CantReadCardFolder(dir.to_path_buf(), err))?;
+            let path = entry.path();
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                visit(typ, &path, seen, result, ignored)?;
+            }
+            else if let Some(id) = get_card_id_from_path(&path) {
+                if let Some(first) = seen.get(&id) {
+                    let reason = format!("{}/{} is already in {}", typ, id, first.display());
+                    ignored.push((path, reason));
+                    continue
                 }
+                seen.insert(id, path.clone());
+                result.push((id, path));
+            }
+            else if path.extension().is_some_and(|extension| extension == "json") {
+                ignored.push((path, String::from("its name is not a card ID")));
             }
         }
-
-        result
+        Ok(())
     }
 
-    fn json(id: u64) -> Result<String, Error> {
-        let path = get_file_path_for_card(Self::typ_str(), id);
-        if path.exists() {
-            fs::read_to_string(path).map_err(|_| Error::CantAccessCard)
-        }
-        else {
-            Err(Error::CantAccessCard)
+    let dir = get_path_to_card_type(typ);
+    let mut seen = HashMap::new();
+    let mut result = Vec::new();
+    let mut ignored = Vec::new();
+    visit(typ, &dir, &mut seen, &mut result, &mut ignored)?;
+
+    let mut reported = IGNORED_FILES.lock().unwrap();
+    reported.retain(|path| !path.starts_with(&dir) || ignored.iter().any(|(other, _)| other == path));
+    for (path, reason) in ignored {
+        if !reported.contains(&path) {
+            println!("Ignoring {} as {}", path.display(), reason);
+            reported.insert(path);
         }
     }
 
-    fn sql_find_id(db: &rusqlite::Connection, name_or_id: &str) -> Result<u64, Error> {
-        fn get_next_id(rows: &mut rusqlite::Rows, name_or_id: &str) -> Result<u64, Error> {
-            match rows.next() {
-                Err(err) => Err(Error::DatabaseError(err.to_string())),
-                Ok(None) => Err(Error::CantFindCard(String::from(name_or_id))),
-                Ok(Some(row)) => row.get::<usize, u64>(0).map_err(|err| Error::DatabaseError(err.to_string())),
+    Ok(result)
+}
+
+// ID of the card stored in the given file or None if it isn't a card file. Editors leave
+// all kinds of files next to the ones they edit (.5.json.swp, 5.json~, #5.json#,
+// 5.json.tmp, ...); none of those parse as <id>.json.
+pub fn get_card_id_from_path(path: &Path) -> Option<u64> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+// Path of a card file relative to its type folder, as stored in CardFiles.
+pub fn get_relative_card_path(typ: &str, path: &Path) -> String {
+    let relative = path.strip_prefix(get_path_to_card_type(typ)).unwrap_or(path);
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+// Where the index says the card is. Quarantined cards aren't indexed but where their file is
+// has been recorded along with the error. Falls back to the default location for cards that
+// the index knows nothing about.
+pub fn sql_find_card_file(db: &rusqlite::Connection, typ: &str, code: u32, id: u64) -> Result<PathBuf, Error> {
+    let find_in = |table: &str| db.query_row(&format!("SELECT path FROM {} WHERE card_type IS ?1 AND card_id IS ?2", table), params![code, id],
+                                             |row| row.get::<usize, Option<String>>(0))
+        .optional()
+        .map_err(Error::DatabaseError)
+        .map(Option::flatten);
+    let relative = match find_in("CardFiles")? {
+        Some(relative) => Some(relative),
+        None => find_in("IndexErrors")?,
+    };
+    Ok(match relative {
+        Some(relative) => get_path_to_card_type(typ).join(relative),
+        None => get_file_path_for_card(typ, id),
+    })
+}
+
+pub fn read_card_json(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|err| Error::CantAccessCard(path.to_path_buf(), err))
+}
+
+// Write a card file such that readers (and the watcher) either see the old or the new
+// contents but never a partially written file. The contents are written to a temporary
+// file (named so that the watcher ignores it) which `validate` gets to look at before it
+// replaces the card file.
+pub fn write_card_file<F: FnOnce(&Path) -> Result<(), Error>>(path: &Path, contents: &serde_json::Value, validate: F) -> Result<(), Error> {
+    use std::io::Write;
+
+    let invalid_path = || Error::CantAccessCard(path.to_path_buf(), std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file path"));
+    let dir = path.parent().ok_or_else(invalid_path)?;
+    fs::create_dir_all(dir).map_err(|err| Error::CantAccessCard(dir.to_path_buf(), err))?;
+
+    let file_name = path.file_name().ok_or_else(invalid_path)?.to_string_lossy();
+    let temp_path = dir.join(format!(".{}.tmp", file_name));
+
+    let json = serde_json::to_string_pretty(contents).map_err(|err| Error::CantReadFormatOfCard(path.to_path_buf(), err))?;
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        })
+        .map_err(|err| Error::CantAccessCard(temp_path.clone(), err))
+        .and_then(|_| validate(&temp_path))
+        .and_then(|_| fs::rename(&temp_path, path).map_err(|err| Error::CantAccessCard(path.to_path_buf(), err)));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+// Cheap check for whether a card file has changed: modification time (in nanoseconds
+// since the epoch) and size.
+pub fn get_card_file_stamp(path: &Path) -> Result<(i64, i64), Error> {
+    let metadata = fs::metadata(path)
+        .map_err(|err| Error::CantAccessCard(path.to_path_buf(), err))?;
+    let mtime = metadata.modified()
+        .map_err(|err| Error::CantAccessCard(path.to_path_buf(), err))?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0);
+    Ok((mtime, metadata.len() as i64))
+}
+
+// Expensive check for whether a card file has changed: hash of its contents.
+pub fn get_card_file_hash(path: &Path) -> Result<i64, Error> {
+    let contents = fs::read(path)
+        .map_err(|err| Error::CantAccessCard(path.to_path_buf(), err))?;
+    Ok(hash_contents(&contents))
+}
+
+pub fn sql_find_card_id(db: &rusqlite::Connection, typ: &str, table: &str, name_or_id: &str) -> Result<u64, Error> {
+    fn get_next_id(rows: &mut rusqlite::Rows, name_or_id: &str) -> Result<u64, Error> {
+        match rows.next() {
+            Err(err) => Err(Error::DatabaseError(err)),
+            Ok(None) => Err(Error::CantFindCard(String::from(name_or_id))),
+            Ok(Some(row)) => row.get::<usize, u64>(0).map_err(Error::DatabaseError),
+        }
+    }
+    if let Ok(id) = name_or_id.parse::<u64>() {
+        Ok(id)
+    }
+    else {
+        let mut stmt = db.prepare(&format!("SELECT id FROM {} WHERE title LIKE ?1", table))
+            .map_err(Error::DatabaseError)?;
+        let result = match stmt.query(params![format!("%{}%", name_or_id)]) {
+            Err(e) => Err(Error::DatabaseError(e)),
+            Ok(mut rows) => {
+                let first = get_next_id(&mut rows, name_or_id);
+                let second = get_next_id(&mut rows, name_or_id);
+
+                match (first, second) {
+                    (Ok(_), Ok(_)) => Err(Error::CantFindCard(format!("Name '{}/{}' is ambiguous", typ, name_or_id))),
+                    (f, _) => f
+                }
             }
+        };
+        result
+    }
+}
+
+pub const MAX_LIST_LIMIT: u64 = 10000;
+
+// Paging, ordering and projection of list results. These come in as query string parameters
+// alongside the filters and are taken out before the rest is turned into conditions.
+//
+//   limit=<n>                at most n results
+//   offset=<n>               skip the first n results
+//   sort=<col>[:desc],...    order by the given columns (ascending unless :desc)
+//   fields=<col>,...         return rows with these columns rather than bare IDs
+//
+// Results are always ordered (by ID if nothing else) so that pages don't overlap. `filter`
+// is for conditions the server adds itself, e.g. the date range of an export.
+#[derive(Default)]
+pub struct ListOptions {
+    pub limit: Option<u64>,
+    pub offset: u64,
+    pub sort: Vec<(String, bool)>,
+    pub fields: Option<Vec<String>>,
+    pub filter: Option<crate::query::Expr>,
+}
+
+impl ListOptions {
+    pub fn take_from_query(query: &mut HashMap<String, String>) -> Result<ListOptions, Error> {
+        let mut options = ListOptions::default();
+        if let Some(limit) = query.remove("limit") {
+            options.limit = Some(limit.parse::<u64>().ok()
+                .filter(|limit| (1..=MAX_LIST_LIMIT).contains(limit))
+                .ok_or_else(|| Error::InvalidQuery(format!("limit must be between 1 and {}", MAX_LIST_LIMIT)))?);
         }
-        if let Ok(id) = name_or_id.parse::<u64>() {
-            Ok(id)
+        if let Some(offset) = query.remove("offset") {
+            options.offset = offset.parse::<u64>()
+                .map_err(|_| Error::InvalidQuery(String::from("offset must be a number")))?;
         }
-        else {
-            let mut stmt = db.prepare(&format!("SELECT id FROM {} WHERE title LIKE '%{}%'", Self::sql_table(), name_or_id))
-                .map_err(|err| Error::DatabaseError(err.to_string()))?;
-            let result = match stmt.query([]) {
-                Err(e) => Err(Error::DatabaseError(e.to_string())),
-                Ok(mut rows) => {
-                    let first = get_next_id(&mut rows, name_or_id);
-                    let second = get_next_id(&mut rows, name_or_id);
-
-                    match (first, second) {
-                        (Ok(_), Ok(_)) => Err(Error::CantFindCard(format!("Name '{}/{}' is ambiguous", Self::typ_str(), name_or_id))),
-                        (f, _) => f
-                    }
-                }
-            };
-            result
+        if let Some(sort) = query.remove("sort") {
+            for term in sort.split(',').map(|term| term.trim()).filter(|term| !term.is_empty()) {
+                let (column, descending) = match term.split_once(':') {
+                    None => (term, false),
+                    Some((column, "asc")) => (column, false),
+                    Some((column, "desc")) => (column, true),
+                    Some(_) => return Err(Error::InvalidQuery(format!("sort order in '{}' must be asc or desc", term))),
+                };
+                options.sort.push((String::from(column), descending));
+            }
+        }
+        if let Some(fields) = query.remove("fields") {
+            options.fields = Some(fields.split(',')
+                .map(|field| String::from(field.trim()))
+                .filter(|field| !field.is_empty())
+                .collect());
         }
+        Ok(options)
     }
+}
 
-    fn sql_list_ids(db: &rusqlite::Connection, query: &HashMap<String, String>) -> Result<Vec<u64>, Error> {
+fn find_column<'a>(columns: &'a [String], name: &str) -> Result<&'a str, Error> {
+    columns.iter()
+        .find(|column| column.eq_ignore_ascii_case(name))
+        .map(|column| column.as_str())
+        .ok_or_else(|| Error::InvalidQuery(format!("unknown property '{}'", name)))
+}
 
-        let mut tags = Vec::new();
-        let mut ids = Vec::new();
+pub fn json_from_sql_value(value: rusqlite::types::Value) -> serde_json::Value {
+    use rusqlite::types::Value;
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::json!(i),
+        Value::Real(r) => serde_json::json!(r),
+        Value::Text(s) => serde_json::Value::String(s),
+        Value::Blob(_) => serde_json::Value::Null,
+    }
+}
 
-        let mut stmt_str = format!("SELECT id FROM {}", Self::sql_table());
-        let mut have_where_clause = false;
-        if !query.is_empty() {
-            for (key, value) in query.iter() {
-                if key == "tag" {
-                    tags.push(value)
-                }
-                else if key == "_where" {
-                    stmt_str = format!("{} {} {}", stmt_str, if have_where_clause { "AND" } else { "WHERE" }, decode(value).unwrap());
-                    have_where_clause = true;
-                }
-                else {
-                    ////REVIEW: stringify automatically?
-                    stmt_str = format!("{} {} {} IS {}", stmt_str, if have_where_clause { "AND" } else { "WHERE" }, key, decode(value).unwrap());
-                    have_where_clause = true;
-                }
-            }
+// Run a list query against `table` (see sql_query_conditions() and ListOptions) and return
+// the given columns of the matching rows. `key` are the columns that identify a row; they
+// break ties when sorting.
+fn sql_query_rows(db: &rusqlite::Connection, table: &str, type_code: &str, key: &[&str], select: &[&str],
+                  query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<Vec<rusqlite::types::Value>>, Error> {
+
+    let mut stmt_params = Vec::new();
+    let mut conditions = sql_query_conditions(db, table, type_code, query, &mut stmt_params)?;
+    let columns = sql_table_columns(db, table)?;
+    if let Some(filter) = &options.filter {
+        let context = crate::query::SqlContext { columns: &columns, type_code: String::from(type_code) };
+        conditions.push(filter.to_sql(&context, &mut stmt_params)?);
+    }
+
+    let select = select.iter()
+        .map(|column| find_column(&columns, column))
+        .collect::<Result<Vec<&str>, Error>>()?;
+    let mut stmt_str = format!("SELECT {} FROM {}", select.join(", "), table);
+    if !conditions.is_empty() {
+        stmt_str = format!("{} WHERE {}", stmt_str, conditions.join(" AND "));
+    }
+
+    let mut order = Vec::new();
+    for (column, descending) in options.sort.iter() {
+        order.push(format!("{} {}", find_column(&columns, column)?, if *descending { "DESC" } else { "ASC" }));
+    }
+    order.extend(key.iter().map(|column| String::from(*column)));
+    stmt_str = format!("{} ORDER BY {}", stmt_str, order.join(", "));
+
+    if options.limit.is_some() || options.offset > 0 {
+        // SQLite has no OFFSET without LIMIT; -1 means no limit.
+        stmt_params.push(rusqlite::types::Value::Integer(options.limit.map(|limit| limit as i64).unwrap_or(-1)));
+        stmt_params.push(rusqlite::types::Value::Integer(options.offset as i64));
+        stmt_str = format!("{} LIMIT ?{} OFFSET ?{}", stmt_str, stmt_params.len() - 1, stmt_params.len());
+    }
+
+    let mut stmt = db.prepare(stmt_str.as_str())
+        .map_err(Error::DatabaseError)?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(stmt_params.iter()))
+        .map_err(Error::DatabaseError)?;
+
+    let mut result = Vec::new();
+    while let Some(row) = rows.next().map_err(Error::DatabaseError)? {
+        let mut values = Vec::with_capacity(select.len());
+        for index in 0..select.len() {
+            values.push(row.get::<usize, rusqlite::types::Value>(index).map_err(Error::DatabaseError)?);
         }
+        result.push(values);
+    }
 
-        // Look up all tag IDs.
-        let mut tag_ids = Vec::new();
-        if !tags.is_empty() {
-            let mut tag_lookup = db.prepare("SELECT rowid FROM Tags WHERE name LIKE ?1")
-                .map_err(|err| Error::DatabaseError(err.to_string()))?;
-            for tag in tags.iter() {
-                let id = tag_lookup.query_row(params![*tag],
-                    |row| row.get::<usize, u64>(0))
-                    .optional()
-                    .map_err(|err| Error::DatabaseError(err.to_string()))?;
-
-                match id {
-                    Some(i) => tag_ids.push(i),
-                    _ => ()
-                }
-            }
+    Ok(result)
+}
 
-            // If we have tag constraints but none of the tags resulted in any hit,
-            // our result set is empty so early out.
-            if tag_ids.is_empty() {
-                return Ok(ids)
-            }
+fn sql_id_from_value(value: &rusqlite::types::Value, column: usize) -> Result<u64, Error> {
+    match value {
+        rusqlite::types::Value::Integer(id) => Ok(*id as u64),
+        other => Err(Error::DatabaseError(rusqlite::Error::InvalidColumnType(column, String::from("id"), other.data_type()))),
+    }
+}
+
+pub fn sql_list_card_ids(db: &rusqlite::Connection, code: u32, table: &str, query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<u64>, Error> {
+    sql_query_rows(db, table, &code.to_string(), &["id"], &["id"], query, options)?
+        .iter()
+        .map(|row| sql_id_from_value(&row[0], 0))
+        .collect()
+}
+
+// Rows of the given table with the columns in `options.fields` (plus id) as JSON objects.
+pub fn sql_list_card_rows(db: &rusqlite::Connection, code: u32, table: &str, query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<serde_json::Value>, Error> {
+    let mut select = vec!["id"];
+    for field in options.fields.iter().flatten() {
+        if !select.iter().any(|column| column.eq_ignore_ascii_case(field)) {
+            select.push(field.as_str());
         }
+    }
+    Ok(sql_query_rows(db, table, &code.to_string(), &["id"], &select, query, options)?
+        .into_iter()
+        .map(|row| {
+            let mut object = serde_json::Map::new();
+            for (column, value) in select.iter().zip(row) {
+                object.insert(column.to_lowercase(), json_from_sql_value(value));
+            }
+            serde_json::Value::Object(object)
+        })
+        .collect())
+}
 
-        // If we are searching by tags, append lookup for Taggings table.
-        if !tag_ids.is_empty() {
-            for tag_id in tag_ids.iter() {
-                stmt_str = format!("{} {} id IN (SELECT card_id FROM Taggings WHERE tag_id IS {} AND card_type IS {})",
-                                   stmt_str, if have_where_clause { "AND" } else { "WHERE" },
-                    tag_id, Self::typ() as u32
-                );
-                have_where_clause = true;
+// Same as sql_list_card_ids() but across all card types. Only the fields every card has
+// (plus "type") can be filtered on. Returns qualified IDs.
+pub fn sql_list_cards(db: &rusqlite::Connection, query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<String>, Error> {
+    Ok(sql_list_typed_card_ids(db, query, options)?.iter()
+        .map(|(typ, id)| format!("{}/{}", typ, id))
+        .collect())
+}
+
+// Same as sql_list_cards() but returns type and ID separately.
+pub fn sql_list_typed_card_ids(db: &rusqlite::Connection, query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<(String, u64)>, Error> {
+    sql_query_rows(db, "Cards", "Cards.card_type", &["card_type", "id"], &["type", "id"], query, options)?
+        .iter()
+        .map(|row| match &row[0] {
+            rusqlite::types::Value::Text(typ) => Ok((typ.clone(), sql_id_from_value(&row[1], 1)?)),
+            other => Err(Error::DatabaseError(rusqlite::Error::InvalidColumnType(0, String::from("card_type"), other.data_type()))),
+        })
+        .collect()
+}
+
+// Same as sql_list_card_rows() but across all card types. Rows come with the qualified ID
+// of their card as "card".
+pub fn sql_list_all_card_rows(db: &rusqlite::Connection, query: &HashMap<String, String>, options: &ListOptions) -> Result<Vec<serde_json::Value>, Error> {
+    let mut select = vec!["type", "id"];
+    let fields_start = select.len();
+    for field in options.fields.iter().flatten() {
+        select.push(field.as_str());
+    }
+    sql_query_rows(db, "Cards", "Cards.card_type", &["card_type", "id"], &select, query, options)?
+        .into_iter()
+        .map(|row| {
+            let mut object = serde_json::Map::new();
+            let typ = json_from_sql_value(row[0].clone());
+            let id = sql_id_from_value(&row[1], 1)?;
+            object.insert(String::from("card"), serde_json::json!(format!("{}/{}", typ.as_str().unwrap_or_default(), id)));
+            for (column, value) in select.iter().zip(row).skip(fields_start) {
+                object.insert(column.to_lowercase(), json_from_sql_value(value));
             }
+            Ok(serde_json::Value::Object(object))
+        })
+        .collect()
+}
+
+// Turn the parameters of a list request into SQL conditions on `table`. `type_code` is the
+// SQL expression giving the type code of a row. Values end up in `stmt_params`.
+//
+//   tag=<tag>                cards tagged with <tag>
+//   linked-to=<type>/<id>    cards linking to the given card
+//   _where=<query>           cards matching the given query (see query.rs)
+//   <property>=<value>       cards where the property has the given value
+fn sql_query_conditions(db: &rusqlite::Connection, table: &str, type_code: &str, query: &HashMap<String, String>,
+                        stmt_params: &mut Vec<rusqlite::types::Value>) -> Result<Vec<String>, Error> {
+
+    let mut conditions = Vec::new();
+    if query.is_empty() {
+        return Ok(conditions);
+    }
+
+    let columns = sql_table_columns(db, table)?;
+    let context = crate::query::SqlContext { columns: &columns, type_code: String::from(type_code) };
+
+    for (key, value) in query.iter() {
+        let value = decode(value)
+            .map_err(|_| Error::InvalidQuery(format!("value of '{}' is not valid UTF-8", key)))?;
+        if key == "tag" {
+            conditions.push(crate::query::Expr::Tag(value.into_owned()).to_sql(&context, stmt_params)?);
+        }
+        else if key == "linked-to" {
+            let (typ, id) = value.split_once('/')
+                .and_then(|(typ, id)| id.parse::<u64>().ok().map(|id| (typ, id)))
+                .ok_or_else(|| Error::InvalidQuery(String::from("linked-to expects <type>/<id>")))?;
+            conditions.push(crate::query::Expr::LinkedTo(None, String::from(typ), id).to_sql(&context, stmt_params)?);
         }
+        else if key == "_where" {
+            conditions.push(crate::query::parse(&value)?.to_sql(&context, stmt_params)?);
+        }
+        else {
+            // Only allow filtering on actual columns and never splice the value into
+            // the statement.
+            let column = find_column(&columns, key)?;
+            stmt_params.push(sql_value_from_query(&value));
+            conditions.push(format!("{} IS ?{}", column, stmt_params.len()));
+        }
+    }
 
-        let mut stmt = db.prepare(stmt_str.as_str())
-            .map_err(|err| Error::DatabaseError(err.to_string()))?;
-        let mut rows = stmt.query([])
-            .map_err(|err| Error::DatabaseError(err.to_string()))?;
+    Ok(conditions)
+}
 
-        while let Some(row) = rows.next().map_err(|err| Error::DatabaseError(err.to_string()))? {
-            ids.push(row.get::<usize, u64>(0).map_err(|err| Error::DatabaseError(err.to_string()))?)
-        }
+pub fn sql_table_columns(db: &rusqlite::Connection, table: &str) -> Result<Vec<String>, Error> {
+    let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table))
+        .map_err(Error::DatabaseError)?;
+    let columns = stmt.query_map([], |row| row.get::<&str, String>("name"))
+        .map_err(Error::DatabaseError)?
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(Error::DatabaseError)?;
+    Ok(columns)
+}
 
-        Ok(ids)
+// Turn a property value from a query string into something we can bind. Values used to be
+// spliced into the SQL verbatim so keep accepting what worked then: NULL, TRUE/FALSE and
+// single-quoted strings. Anything else is bound as text and left to SQLite's type affinity
+// to compare against numeric columns.
+fn sql_value_from_query(value: &str) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    if value.eq_ignore_ascii_case("null") {
+        Value::Null
+    }
+    else if value.eq_ignore_ascii_case("true") {
+        Value::Integer(1)
+    }
+    else if value.eq_ignore_ascii_case("false") {
+        Value::Integer(0)
+    }
+    else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        Value::Text(value[1..(value.len() - 1)].replace("''", "'"))
+    }
+    else {
+        Value::Text(String::from(value))
     }
+}
 
-    fn sql_write_links(&self, db: &mut rusqlite::Statement) -> Result<(), Error> {
-        for v in self.links() {
-            let colon = v.find(':');
-            let role = match colon {
-                Some(index) => &v[..index],
-                None => "",
-            };
-            let qualified_id = match colon {
-                Some(index) => &v[(index + 1)..],
-                None => &v[..],
-            };
-            let (to_type, to_id) = parse_qualified_id(qualified_id)?;
-
-            db.insert(params![
-                role,
-                Self::typ() as u32,
-                self.id(),
-                to_type as u32,
-                to_id,
-            ]).map_err(|err| Error::DatabaseError(String::from(format!("cannot insert link: {}", err.to_string()))))?;
-        }
-        Ok(())
+// Split a link ("role:type/id" or just "type/id") into role, type code and ID.
+pub fn parse_link(link: &str) -> Result<(&str, u32, u64), Error> {
+    let colon = link.find(':');
+    let role = match colon {
+        Some(index) => &link[..index],
+        None => "",
+    };
+    let qualified_id = match colon {
+        Some(index) => &link[(index + 1)..],
+        None => link,
+    };
+    let (typ, id) = parse_qualified_id(qualified_id)?;
+    Ok((role, typ, id))
+}
+
+pub fn validate_card_links(links: std::slice::Iter<'_, String>) -> Result<(), Error> {
+    for link in links {
+        parse_link(link).map_err(|_| Error::InvalidCard(format!("'{}' is not a valid link", link)))?;
     }
+    Ok(())
+}
 
-    fn sql_write_tags(&self, tag_insert: &mut rusqlite::Statement, tag_lookup: &mut rusqlite::Statement, tagging_insert: &mut rusqlite::Statement) -> Result<(), Error> {
-        for tag in self.tags() {
-            tag_insert.execute(params![tag])
-                .map_err(|err| Error::DatabaseError(String::from(format!("cannot insert tag: {}", err.to_string()))))?;
-            let tag_id = tag_lookup.query_row(params![tag],
-                |row| row.get::<usize, usize>(0))
-                .map_err(|err| Error::DatabaseError(String::from(format!("cannot query tag: {}", err.to_string()))))?;
-
-            tagging_insert.insert(params![
-                tag_id,
-                Self::typ() as u32,
-                self.id()
-            ]).map_err(|err| Error::DatabaseError(String::from(format!("cannot insert tagging: {}", err.to_string()))))?;
-        }
-        Ok(())
+pub fn sql_write_card_links(code: u32, id: u64, links: std::slice::Iter<'_, String>, db: &mut rusqlite::Statement) -> Result<(), Error> {
+    for v in links {
+        let (role, to_type, to_id) = parse_link(v)
+            .map_err(|_| Error::InvalidCard(format!("'{}' is not a valid link", v)))?;
+
+        db.insert(params![
+            role,
+            code,
+            id,
+            to_type,
+            to_id,
+        ]).map_err(Error::DatabaseError)?;
     }
+    Ok(())
+}
+
+pub fn sql_write_card_tags(code: u32, id: u64, tags: std::slice::Iter<'_, String>, tag_insert: &mut rusqlite::Statement, tag_lookup: &mut rusqlite::Statement, tagging_insert: &mut rusqlite::Statement) -> Result<(), Error> {
+    for tag in tags {
+        tag_insert.execute(params![tag])
+            .map_err(Error::DatabaseError)?;
+        let tag_id = tag_lookup.query_row(params![tag],
+            |row| row.get::<usize, usize>(0))
+            .map_err(Error::DatabaseError)?;
+
+        tagging_insert.insert(params![
+            tag_id,
+            code,
+            id
+        ]).map_err(Error::DatabaseError)?;
+    }
+    Ok(())
 }
 
 pub struct Project {
@@ -421,8 +947,8 @@ impl Card for Project {
     fn typ() -> CardType { CardType::Project }
     fn typ_str() -> &'static str { "project" }
 
-    fn load(id: u64) -> Result<Project, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Project, Error> {
+        load_card_from_json(path, id,
             |data| Ok(Project {
                 id,
                 title: data.title,
@@ -437,23 +963,6 @@ impl Card for Project {
             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Projects;
-        DROP INDEX IF EXISTS ProjectsByName;
-        CREATE TABLE Projects (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            started DATETIME,
-            finished DATETIME,
-            active BOOLEAN DEFAULT 1
-        );
-        CREATE INDEX ProjectsByName ON Projects(title);"#
-    }
-
     fn sql_table() -> &'static str { "Projects" }
 
     fn sql_write_stmt() -> &'static str {
@@ -470,7 +979,7 @@ impl Card for Project {
             self.started,
             self.finished,
             self.active,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -498,8 +1007,8 @@ impl Card for Task {
     fn typ() -> CardType { CardType::Task }
     fn typ_str() -> &'static str { "task" }
 
-    fn load(id: u64) -> Result<Task, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Task, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Task {
                                 id,
                                 description: data.title,
@@ -513,22 +1022,6 @@ impl Card for Task {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Tasks;
-        DROP INDEX IF EXISTS TasksByDescription;
-        CREATE TABLE Tasks (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            completed DATETIME,
-            obsolete BOOLEAN
-        );
-        CREATE INDEX TasksByDescription ON Tasks(title);"#
-    }
-
     fn sql_table() -> &'static str { "Tasks" }
 
     fn sql_write_stmt() -> &'static str {
@@ -544,7 +1037,7 @@ impl Card for Task {
             self.source,
             self.completed,
             self.obsolete,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -573,8 +1066,8 @@ impl Card for Timelog {
     fn typ() -> CardType { CardType::Timelog }
     fn typ_str() -> &'static str { "timelog" }
 
-    fn load(id: u64) -> Result<Timelog, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Timelog, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Timelog {
                                 id,
                                 description: data.title,
@@ -589,21 +1082,6 @@ impl Card for Timelog {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Timelogs;
-        CREATE TABLE Timelogs (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            started DATETIME NOT NULL,
-            ended DATETIME,
-            category VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Timelogs" }
 
     fn sql_write_stmt() -> &'static str {
@@ -620,7 +1098,7 @@ impl Card for Timelog {
             self.started,
             self.ended,
             self.category,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -648,8 +1126,8 @@ impl Card for Status {
     fn typ() -> CardType { CardType::Status }
     fn typ_str() -> &'static str { "status" }
 
-    fn load(id: u64) -> Result<Status, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Status, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Status {
                                 id,
                                 message: data.title,
@@ -663,20 +1141,6 @@ impl Card for Status {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Statuses;
-        CREATE TABLE Statuses (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            began DATETIME,
-            ended DATETIME
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Statuses" }
 
     fn sql_write_stmt() -> &'static str {
@@ -692,7 +1156,7 @@ impl Card for Status {
             self.source,
             self.began,
             self.ended,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -724,8 +1188,8 @@ impl Card for Book {
     fn typ() -> CardType { CardType::Book }
     fn typ_str() -> &'static str { "book" }
 
-    fn load(id: u64) -> Result<Book, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Book, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Book {
                                 id,
                                 title: data.title,
@@ -743,24 +1207,6 @@ impl Card for Book {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Books;
-        CREATE TABLE Books (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            authors VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            year INTEGER,
-            started DATETIME,
-            completed DATETIME,
-            cover VARCHAR,
-            ident VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Books" }
 
     fn sql_write_stmt() -> &'static str {
@@ -780,7 +1226,7 @@ impl Card for Book {
             self.completed,
             self.cover,
             self.ident_code
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -811,8 +1257,8 @@ impl Card for Purchase {
     fn typ() -> CardType { CardType::Purchase }
     fn typ_str() -> &'static str { "purchase" }
 
-    fn load(id: u64) -> Result<Purchase, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Purchase, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Purchase {
                                 id,
                                 item: data.title,
@@ -829,23 +1275,6 @@ impl Card for Purchase {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Purchases;
-        CREATE TABLE Purchases (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            date DATETIME,
-            price REAL,
-            currency CHAR(3),
-            used BOOLEAN,
-            store VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Purchases" }
 
     fn sql_write_stmt() -> &'static str {
@@ -864,7 +1293,7 @@ impl Card for Purchase {
             self.currency,
             self.used,
             self.store,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -892,8 +1321,8 @@ impl Card for Metric {
     fn typ() -> CardType { CardType::Metric }
     fn typ_str() -> &'static str { "metric" }
 
-    fn load(id: u64) -> Result<Metric, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Metric, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Metric {
                                 id,
                                 name: data.title,
@@ -907,20 +1336,6 @@ impl Card for Metric {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Metrics;
-        CREATE TABLE Metrics (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            timestamp DATETIME,
-            amount REAL
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Metrics" }
 
     fn sql_write_stmt() -> &'static str {
@@ -936,7 +1351,7 @@ impl Card for Metric {
             self.source,
             self.timestamp,
             self.amount,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -965,8 +1380,8 @@ impl Card for Word {
     fn typ() -> CardType { CardType::Word }
     fn typ_str() -> &'static str { "word" }
 
-    fn load(id: u64) -> Result<Word, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Word, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Word {
                                 id,
                                 word: data.title,
@@ -981,21 +1396,6 @@ impl Card for Word {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Words;
-        CREATE TABLE Words (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            language CHAR(3),
-            category VARCHAR,
-            gender CHAR(1)
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Words" }
 
     fn sql_write_stmt() -> &'static str {
@@ -1012,7 +1412,7 @@ impl Card for Word {
             self.language,
             self.category,
             self.gender,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -1039,8 +1439,8 @@ impl Card for Note {
     fn typ() -> CardType { CardType::Note }
     fn typ_str() -> &'static str { "note" }
 
-    fn load(id: u64) -> Result<Note, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Note, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Note {
                                 id,
                                 title: data.title,
@@ -1053,19 +1453,6 @@ impl Card for Note {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Notes;
-        CREATE TABLE Notes (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            text VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Notes" }
 
     fn sql_write_stmt() -> &'static str {
@@ -1080,7 +1467,7 @@ impl Card for Note {
             self.modified,
             self.source,
             self.text,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -1106,8 +1493,8 @@ impl Card for Thought {
     fn typ() -> CardType { CardType::Thought }
     fn typ_str() -> &'static str { "thought" }
 
-    fn load(id: u64) -> Result<Thought, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Thought, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Thought {
                                 id,
                                 description: data.title,
@@ -1119,18 +1506,6 @@ impl Card for Thought {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Thoughts;
-        CREATE TABLE Thoughts (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Thoughts" }
 
     fn sql_write_stmt() -> &'static str {
@@ -1144,7 +1519,7 @@ impl Card for Thought {
             self.created,
             self.modified,
             self.source,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -1171,8 +1546,8 @@ impl Card for Achievement {
     fn typ() -> CardType { CardType::Achievement }
     fn typ_str() -> &'static str { "achievement" }
 
-    fn load(id: u64) -> Result<Achievement, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Achievement, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Achievement {
                                 id,
                                 description: data.title,
@@ -1185,19 +1560,6 @@ impl Card for Achievement {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Achievements;
-        CREATE TABLE Achievements (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            date VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Achievements" }
 
     fn sql_write_stmt() -> &'static str {
@@ -1212,7 +1574,7 @@ impl Card for Achievement {
             self.modified,
             self.source,
             self.date,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
     }
 }
 
@@ -1244,8 +1606,8 @@ impl Card for Notebook {
     fn typ() -> CardType { CardType::Notebook }
     fn typ_str() -> &'static str { "notebook" }
 
-    fn load(id: u64) -> Result<Notebook, Error> {
-        load_card_from_json(id,
+    fn load(path: &Path, id: u64) -> Result<Notebook, Error> {
+        load_card_from_json(path, id,
                             |data| Ok(Notebook {
                                 id,
                                 title: data.title,
@@ -1263,24 +1625,6 @@ impl Card for Notebook {
                             }))
     }
 
-    fn sql_schema() -> &'static str {
-        r#"
-        DROP TABLE IF EXISTS Notebooks;
-        CREATE TABLE Notebooks (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR NOT NULL,
-            created DATETIME NOT NULL,
-            modified DATETIME NOT NULL,
-            source VARCHAR,
-            description VARCHAR,
-            location VARCHAR,
-            format VARCHAR,
-            pages INTEGER,
-            started VARCHAR,
-            ended VARCHAR
-        );"#
-    }
-
     fn sql_table() -> &'static str { "Notebooks" }
 
     fn sql_write_stmt() -> &'static str {
@@ -1300,6 +1644,38 @@ impl Card for Notebook {
             self.pages,
             self.started,
             self.ended,
-        ]).map_err(|err| Error::DatabaseError(err.to_string()))
+        ]).map_err(Error::DatabaseError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(mut target: serde_json::Value, patch: serde_json::Value) -> serde_json::Value {
+        merge_patch(&mut target, &patch);
+        target
+    }
+
+    #[test]
+    fn merge_patch_removes_properties_set_to_null() {
+        assert_eq!(patched(json!({"a": 1, "b": 2}), json!({"a": null})), json!({"b": 2}));
+        assert_eq!(patched(json!({"a": 1}), json!({"missing": null})), json!({"a": 1}));
+    }
+
+    #[test]
+    fn merge_patch_merges_nested_objects() {
+        assert_eq!(patched(json!({"a": {"b": 1, "c": 2}, "d": 3}), json!({"a": {"b": 4, "c": null, "e": 5}})),
+                   json!({"a": {"b": 4, "e": 5}, "d": 3}));
+        assert_eq!(patched(json!({"a": "text"}), json!({"a": {"b": 1}})), json!({"a": {"b": 1}}));
+    }
+
+    #[test]
+    fn merge_patch_replaces_arrays_and_other_values_whole() {
+        assert_eq!(patched(json!({"a": [1, 2, 3]}), json!({"a": [4]})), json!({"a": [4]}));
+        assert_eq!(patched(json!({"a": [{"b": 1}]}), json!({"a": [{"c": 2}]})), json!({"a": [{"c": 2}]}));
+        assert_eq!(patched(json!({"a": 1}), json!({"a": "one"})), json!({"a": "one"}));
+        assert_eq!(patched(json!({"a": 1}), json!([1])), json!([1]));
     }
 }
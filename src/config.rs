@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Configuration is layered. Later layers override earlier ones:
+//
+// 1. Built-in defaults
+// 2. TOML config file (--config <path>, $GULPER_CONFIG, or ./gulper.toml if present)
+// 3. Environment variables (GULPER_<KEY>, e.g. GULPER_CARDS or GULPER_REPORT_R_BIN)
+// 4. Command-line flags (--<key>, e.g. --cards or --report-r-bin)
+//
+// Example config file:
+//
+//   cards = "/home/me/Cards"
+//   database = "/var/lib/gulper/cards.sqlite"
+//   listen = "127.0.0.1:8000"
+//
+//   [watch]
+//   debounce = 500                # ms to wait for more changes to a card file before indexing it
+//   rescan = 3600                 # s between full rescans of the card folders (0 to turn off)
+//
+//   [report]
+//   enabled = true
+//   r_bin = "/usr/bin"
+//   quarto_bin = "/usr/lib/rstudio/bin/quarto/bin"
+//   rmd = "/home/me/R/DailyReport.Rmd"
+//   html = "/home/me/R/DailyReport.html"
+//   folder = "/home/me/Reports/Daily"
+
+const DEFAULT_CONFIG_FILE: &str = "gulper.toml";
+const ENV_PREFIX: &str = "GULPER_";
+
+// Keys as they appear in the config file. Environment variables and command-line
+// flags are derived from these.
+const KEYS: &[&str] = &[
+    "cards",
+    "database",
+    "listen",
+    "watch.debounce",
+    "watch.rescan",
+    "report.enabled",
+    "report.r_bin",
+    "report.quarto_bin",
+    "report.rmd",
+    "report.html",
+    "report.folder",
+];
+
+// Commands given as the first argument. Without one, the server runs.
+const COMMANDS: &[(&str, Command, &str)] = &[
+    ("serve", Command::Serve, "Index the cards and serve the API (default)"),
+    ("check-links", Command::CheckLinks, "List dangling links, links to unknown card types and self-links"),
+    ("export", Command::Export, "Write the cards (or a snapshot of the index) to a file"),
+    ("validate", Command::Validate, "List the card files that don't hold valid cards and why"),
+];
+
+// Options that only mean something to one command. Unlike settings, these can only be given
+// on the command line.
+const COMMAND_OPTIONS: &[(Command, &str, &str)] = &[
+    (Command::Export, "output", "File to export to (required)"),
+    (Command::Export, "format", "ndjson (default), tar or sqlite"),
+    (Command::Export, "type", "Only export cards of this type"),
+    (Command::Export, "where", "Only export cards matching this query"),
+    (Command::Export, "from", "Only export cards created on or after this date"),
+    (Command::Export, "to", "Only export cards created before this date"),
+];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    CheckLinks,
+    Export,
+    Validate,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub command: Command,
+    pub command_options: HashMap<String, String>,
+    pub cards_path: PathBuf,
+    pub db_path: PathBuf,
+    pub listen_addr: SocketAddr,
+    pub watch_debounce: Duration,
+    pub rescan_interval: Option<Duration>,
+    pub report: ReportConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportConfig {
+    pub enabled: bool,
+    pub r_bin_path: PathBuf,
+    pub quarto_bin_path: PathBuf,
+    pub rmd_path: PathBuf,
+    pub html_path: PathBuf,
+    pub folder_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: Command::Serve,
+            command_options: HashMap::new(),
+            cards_path: PathBuf::from("C:/Dropbox/Data/Cards"),
+            db_path: PathBuf::from("cards.sqlite"),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 8000)),
+            watch_debounce: Duration::from_millis(500),
+            rescan_interval: Some(Duration::from_secs(3600)),
+            report: ReportConfig {
+                enabled: true,
+                r_bin_path: PathBuf::from("M:/R/4.1.3/bin/x64"),
+                quarto_bin_path: PathBuf::from("C:/Program Files/RStudio/bin/quarto/bin"),
+                rmd_path: PathBuf::from("C:/Dropbox/Data/R/DailyReport.Rmd"),
+                html_path: PathBuf::from("C:/Dropbox/Data/R/DailyReport.html"),
+                folder_path: PathBuf::from("C:/Dropbox/Data/Reports/Daily"),
+            },
+        }
+    }
+}
+
+impl Config {
+
+    // Build the configuration from all layers given the process arguments (without argv[0]).
+    pub fn load<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+
+        let Args { command, flags, command_options } = parse_args(args)?;
+
+        let mut config = Config { command, command_options, ..Config::default() };
+
+        // Config file.
+        let explicit_file = flags.iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+        match explicit_file {
+            Some(path) => config.apply_file(&path)?,
+            None => {
+                let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if path.exists() {
+                    config.apply_file(&path)?;
+                }
+            }
+        }
+
+        // Environment.
+        for key in KEYS {
+            let var = format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase());
+            if let Ok(value) = env::var(&var) {
+                config.set(key, &value).map_err(|err| format!("{}: {}", var, err))?;
+            }
+        }
+
+        // Command line.
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value).map_err(|err| format!("--{}: {}", key.replace(['.', '_'], "-"), err))?;
+        }
+
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), String> {
+
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read config file {}: {}", path.display(), err))?;
+        let toml: toml::Value = contents.parse()
+            .map_err(|err| format!("Cannot parse config file {}: {}", path.display(), err))?;
+
+        fn flatten(prefix: &str, value: &toml::Value, out: &mut Vec<(String, String)>) {
+            match value {
+                toml::Value::Table(table) => {
+                    for (name, value) in table.iter() {
+                        let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                        flatten(&key, value, out);
+                    }
+                },
+                toml::Value::String(s) => out.push((String::from(prefix), s.clone())),
+                other => out.push((String::from(prefix), other.to_string())),
+            }
+        }
+
+        let mut entries = Vec::new();
+        flatten("", &toml, &mut entries);
+
+        for (key, value) in entries.iter() {
+            self.set(key, value).map_err(|err| format!("{}: {}: {}", path.display(), key, err))?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+
+        fn parse_bool(value: &str) -> Result<bool, String> {
+            match value.to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" => Ok(false),
+                _ => Err(format!("'{}' is not a boolean", value)),
+            }
+        }
+
+        match key {
+            "cards" => self.cards_path = PathBuf::from(value),
+            "database" => self.db_path = PathBuf::from(value),
+            "listen" => self.listen_addr = value.parse::<SocketAddr>()
+                .map_err(|_| format!("'{}' is not a valid address (expected e.g. 127.0.0.1:8000)", value))?,
+            "watch.debounce" => self.watch_debounce = value.parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("'{}' is not a number of milliseconds", value))?,
+            "watch.rescan" => self.rescan_interval = match value.parse::<u64>() {
+                Ok(0) => None,
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => return Err(format!("'{}' is not a number of seconds", value)),
+            },
+            "report.enabled" => self.report.enabled = parse_bool(value)?,
+            "report.r_bin" => self.report.r_bin_path = PathBuf::from(value),
+            "report.quarto_bin" => self.report.quarto_bin_path = PathBuf::from(value),
+            "report.rmd" => self.report.rmd_path = PathBuf::from(value),
+            "report.html" => self.report.html_path = PathBuf::from(value),
+            "report.folder" => self.report.folder_path = PathBuf::from(value),
+            _ => return Err(String::from("unknown setting")),
+        }
+
+        Ok(())
+    }
+}
+
+pub fn usage() -> String {
+    let mut s = String::from("Usage: gulper_index [command] [options]\n\nCommands:\n");
+    for (name, _, description) in COMMANDS {
+        s.push_str(&format!("    {:<16}{}\n", name, description));
+    }
+    s.push_str("\nOptions:\n    --config <path>\n");
+    for key in KEYS {
+        s.push_str(&format!("    --{} <value>\n", key.replace(['.', '_'], "-")));
+    }
+    s.push_str("    --no-report\n    --help\n");
+    for (name, command, _) in COMMANDS {
+        let options: Vec<&(Command, &str, &str)> = COMMAND_OPTIONS.iter().filter(|(c, _, _)| c == command).collect();
+        if !options.is_empty() {
+            s.push_str(&format!("\nOptions for {}:\n", name));
+            for (_, option, description) in options {
+                s.push_str(&format!("    --{:<18}{}\n", format!("{} <value>", option), description));
+            }
+        }
+    }
+    s
+}
+
+struct Args {
+    command: Command,
+    flags: Vec<(String, String)>,
+    command_options: HashMap<String, String>,
+}
+
+// Pick out the command and turn command-line flags into (key, value) pairs using config
+// file key names. Options of the command are returned separately.
+fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Args, String> {
+
+    let mut args = args.peekable();
+    let mut command = Command::Serve;
+    let mut result = Vec::new();
+    let mut options = HashMap::new();
+
+    if let Some(arg) = args.next_if(|arg| !arg.starts_with("--")) {
+        command = COMMANDS.iter()
+            .find(|(name, _, _)| *name == arg)
+            .map(|(_, command, _)| *command)
+            .ok_or_else(|| format!("Unknown command '{}'\n\n{}", arg, usage()))?;
+    }
+
+    while let Some(arg) = args.next() {
+        let flag = arg.strip_prefix("--")
+            .ok_or_else(|| format!("Unexpected argument '{}'", arg))?;
+
+        if flag == "help" {
+            return Err(usage());
+        }
+        if flag == "no-report" {
+            result.push((String::from("report.enabled"), String::from("false")));
+            continue;
+        }
+
+        let (name, inline_value) = match flag.find('=') {
+            Some(index) => (&flag[..index], Some(String::from(&flag[(index + 1)..]))),
+            None => (flag, None),
+        };
+
+        let is_command_option = COMMAND_OPTIONS.iter().any(|(c, option, _)| *c == command && *option == name);
+        let key = if name == "config" || is_command_option {
+            name
+        } else {
+            KEYS.iter().copied().find(|key| key.replace(['.', '_'], "-") == name)
+                .ok_or_else(|| format!("Unknown option --{}\n\n{}", name, usage()))?
+        };
+
+        let value = match inline_value {
+            Some(value) => value,
+            None => args.next().ok_or_else(|| format!("Missing value for --{}", name))?,
+        };
+
+        if is_command_option {
+            options.insert(String::from(key), value);
+        }
+        else {
+            result.push((String::from(key), value));
+        }
+    }
+
+    Ok(Args { command, flags: result, command_options: options })
+}
+
+pub fn init(config: Config) {
+    CONFIG.set(config).expect("Configuration already initialized");
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| String::from(*arg)).collect::<Vec<String>>().into_iter()
+    }
+
+    fn write_config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("gulper-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let path = write_config_file("layers", r#"
+            database = "file.sqlite"
+            listen = "127.0.0.1:1234"
+            [report]
+            html = "file.html"
+            rmd = "file.rmd"
+            "#);
+        env::set_var("GULPER_REPORT_HTML", "env.html");
+        env::set_var("GULPER_REPORT_RMD", "env.rmd");
+
+        let config = Config::load(args(&["--config", path.to_str().unwrap(), "--report-rmd", "flag.rmd"]));
+
+        env::remove_var("GULPER_REPORT_HTML");
+        env::remove_var("GULPER_REPORT_RMD");
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.db_path, PathBuf::from("file.sqlite"));
+        assert_eq!(config.listen_addr, "127.0.0.1:1234".parse::<SocketAddr>().unwrap());
+        assert_eq!(config.report.html_path, PathBuf::from("env.html"));
+        assert_eq!(config.report.rmd_path, PathBuf::from("flag.rmd"));
+        assert_eq!(config.cards_path, Config::default().cards_path);
+    }
+
+    #[test]
+    fn reads_nested_keys_and_non_string_values_from_file() {
+        let path = write_config_file("values", r#"
+            [watch]
+            debounce = 250
+            rescan = 0
+            [report]
+            enabled = false
+            "#);
+        let config = Config::load(args(&["--config", path.to_str().unwrap()]));
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.watch_debounce, Duration::from_millis(250));
+        assert_eq!(config.rescan_interval, None);
+        assert!(!config.report.enabled);
+    }
+
+    #[test]
+    fn reports_where_a_bad_value_came_from() {
+        let path = write_config_file("bad", "listen = \"nowhere\"\n");
+        let error = Config::load(args(&["--config", path.to_str().unwrap()])).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains(path.to_str().unwrap()) && error.contains("listen"), "{}", error);
+
+        let error = Config::load(args(&["--watch-debounce", "soon"])).unwrap_err();
+        assert!(error.starts_with("--watch-debounce:"), "{}", error);
+
+        assert!(Config::load(args(&["--config", "/does/not/exist.toml"])).is_err());
+    }
+
+    #[test]
+    fn separates_command_options_from_settings() {
+        let parsed = parse_args(args(&["export", "--output", "out.tar", "--format=tar", "--cards", "/cards", "--no-report"])).unwrap();
+        assert_eq!(parsed.command, Command::Export);
+        assert_eq!(parsed.flags, vec![
+            (String::from("cards"), String::from("/cards")),
+            (String::from("report.enabled"), String::from("false")),
+        ]);
+        assert_eq!(parsed.command_options.get("output").map(String::as_str), Some("out.tar"));
+        assert_eq!(parsed.command_options.get("format").map(String::as_str), Some("tar"));
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_options() {
+        assert!(parse_args(args(&["frobnicate"])).is_err());
+        assert!(parse_args(args(&["--frobnicate", "1"])).is_err());
+        // Export options don't exist for other commands.
+        assert!(parse_args(args(&["validate", "--output", "out"])).is_err());
+        assert!(parse_args(args(&["--cards"])).is_err());
+    }
+}
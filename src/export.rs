@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::cards::{self, Error};
+use crate::query::{Expr, Literal, Op};
+use crate::registry::{self, CardKind};
+
+// Getting the whole card set (or a slice of it) out in one go, either through the API or
+// the export command.
+//
+// - NDJSON: one card per line, as stored in its file.
+// - Tar: the raw card files under <type>/<path>, i.e. the same layout as the card folder.
+// - SQLite: a point-in-time copy of the index made with SQLite's backup API so it is
+//   consistent even while the indexer keeps writing.
+//
+// NDJSON and tar exports can be narrowed down with the same filters GET /cards takes (type,
+// tag, linked-to, _where, sort, limit, ...) plus "from" and "to" for a range of creation dates.
+
+static SNAPSHOT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ndjson,
+    Tar,
+    Sqlite,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, Error> {
+        match name {
+            "ndjson" => Ok(Format::Ndjson),
+            "tar" => Ok(Format::Tar),
+            "sqlite" => Ok(Format::Sqlite),
+            _ => Err(Error::InvalidQuery(format!("Unknown export format '{}' (expected ndjson, tar or sqlite)", name))),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Tar => "application/x-tar",
+            Format::Sqlite => "application/vnd.sqlite3",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "cards.ndjson",
+            Format::Tar => "cards.tar",
+            Format::Sqlite => "cards.sqlite",
+        }
+    }
+}
+
+fn parse_date(name: &str, value: &str) -> Result<Literal, Error> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(Literal::Date)
+        .map_err(|_| Error::InvalidQuery(format!("{} must be a date (YYYY-MM-DD)", name)))
+}
+
+// The cards to export given the filters. "from" and "to" restrict the creation date
+// (half-open range); everything else filters the same way as for GET /cards.
+pub fn sql_list_export_cards(db: &rusqlite::Connection, filters: &HashMap<String, String>) -> Result<Vec<(&'static dyn CardKind, u64)>, Error> {
+
+    let mut query = filters.clone();
+    let mut conditions = Vec::new();
+    if let Some(from) = query.remove("from") {
+        conditions.push(Expr::Compare(String::from("created"), Op::Ge, parse_date("from", &from)?));
+    }
+    if let Some(to) = query.remove("to") {
+        conditions.push(Expr::Compare(String::from("created"), Op::Lt, parse_date("to", &to)?));
+    }
+
+    let mut result = Vec::new();
+    let mut options = cards::ListOptions::take_from_query(&mut query)?;
+    options.filter = conditions.into_iter()
+        .reduce(|lhs, rhs| Expr::And(Box::new(lhs), Box::new(rhs)));
+    for (typ, id) in cards::sql_list_typed_card_ids(db, &query, &options)? {
+        // The Cards view only has known types in it.
+        if let Some(kind) = registry::get().find(&typ) {
+            result.push((kind, id));
+        }
+    }
+    Ok(result)
+}
+
+pub fn write_ndjson<W: Write>(db: &rusqlite::Connection, cards: &[(&'static dyn CardKind, u64)], out: &mut W) -> Result<(), Error> {
+    for (kind, id) in cards.iter() {
+        let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), *id)?;
+        // Card files are usually pretty-printed; NDJSON needs them on one line.
+        let card: serde_json::Value = serde_json::from_str(&cards::read_card_json(&path)?)
+            .map_err(|err| Error::CantReadFormatOfCard(path, err))?;
+        writeln!(out, "{}", card)
+            .map_err(Error::CantWriteExport)?;
+    }
+    out.flush()
+        .map_err(Error::CantWriteExport)
+}
+
+pub fn write_tar<W: Write>(db: &rusqlite::Connection, cards: &[(&'static dyn CardKind, u64)], out: &mut W) -> Result<(), Error> {
+    let mut builder = tar::Builder::new(out);
+    for (kind, id) in cards.iter() {
+        let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), *id)?;
+        let name = format!("{}/{}", kind.typ_str(), cards::get_relative_card_path(kind.typ_str(), &path));
+        builder.append_path_with_name(&path, &name)
+            .map_err(Error::CantWriteExport)?;
+    }
+    builder.into_inner()
+        .and_then(|out| out.flush())
+        .map_err(Error::CantWriteExport)
+}
+
+pub fn write_snapshot(db: &rusqlite::Connection, path: &Path) -> Result<(), Error> {
+    db.backup(rusqlite::DatabaseName::Main, path, None)
+        .map_err(Error::DatabaseError)
+}
+
+// Copy a snapshot of the index to the given writer. The backup API only writes to databases
+// so this goes through a temporary file.
+pub fn copy_snapshot<W: Write>(db: &rusqlite::Connection, out: &mut W) -> Result<(), Error> {
+    let path = std::env::temp_dir().join(format!("gulper_snapshot_{}_{}.sqlite", std::process::id(),
+                                                 SNAPSHOT_COUNT.fetch_add(1, Ordering::Relaxed)));
+    let result = write_snapshot(db, &path)
+        .and_then(|_| {
+            let mut file = std::fs::File::open(&path).map_err(Error::CantWriteExport)?;
+            std::io::copy(&mut file, out).map_err(Error::CantWriteExport)?;
+            out.flush().map_err(Error::CantWriteExport)
+        });
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+// What to export. Preparing it up front finds problems with the filters before anything
+// has been written.
+pub struct Export {
+    pub format: Format,
+    cards: Vec<(&'static dyn CardKind, u64)>,
+}
+
+impl Export {
+    pub fn prepare(db: &rusqlite::Connection, format: Format, filters: &HashMap<String, String>) -> Result<Export, Error> {
+        let cards = match format {
+            Format::Sqlite if !filters.is_empty() =>
+                return Err(Error::InvalidQuery(String::from("SQLite snapshots always contain the whole index"))),
+            Format::Sqlite => Vec::new(),
+            _ => sql_list_export_cards(db, filters)?,
+        };
+        Ok(Export { format, cards })
+    }
+
+    pub fn write<W: Write>(&self, db: &rusqlite::Connection, out: &mut W) -> Result<(), Error> {
+        match self.format {
+            Format::Ndjson => write_ndjson(db, &self.cards, out),
+            Format::Tar => write_tar(db, &self.cards, out),
+            Format::Sqlite => copy_snapshot(db, out),
+        }
+    }
+}
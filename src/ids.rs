@@ -0,0 +1,55 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{OptionalExtension, params};
+use crate::cards::{self, Error};
+use crate::registry::CardKind;
+
+// Card IDs handed out by the server are the time of allocation in milliseconds since the
+// Unix epoch. That keeps them increasing across restarts without having to store anything
+// and puts them well above the IDs scrapers have been taking from upstream services (Toggl
+// and friends). IDs allocated in the same millisecond are simply counted up from there.
+//
+// Each ID is checked against the index (for all types, so cards can be moved between type
+// folders without clashing) and against the card folder before it is handed out as files
+// may not have been indexed yet.
+//
+// Scrapers that re-import the same upstream data shouldn't have to remember which ID they
+// gave what. The Source URL of a card identifies it just as well; see sql_find_card_by_source().
+
+pub const MAX_IDS_PER_REQUEST: usize = 1000;
+
+static LAST_ID: Mutex<u64> = Mutex::new(0);
+
+fn is_id_taken(kind: &dyn CardKind, id: u64, db: &rusqlite::Connection) -> Result<bool, Error> {
+    let indexed = db.query_row("SELECT 1 FROM CardFiles WHERE card_id IS ?1 LIMIT 1", params![id], |_| Ok(()))
+        .optional()
+        .map_err(Error::DatabaseError)?
+        .is_some();
+    Ok(indexed || cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?.exists())
+}
+
+pub fn allocate_id(kind: &dyn CardKind, db: &rusqlite::Connection) -> Result<u64, Error> {
+    let mut last = LAST_ID.lock().unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0);
+    let mut id = std::cmp::max(now, *last + 1);
+    while is_id_taken(kind, id, db)? {
+        id += 1;
+    }
+    *last = id;
+    Ok(id)
+}
+
+pub fn allocate_ids(kind: &dyn CardKind, count: usize, db: &rusqlite::Connection) -> Result<Vec<u64>, Error> {
+    (0..count).map(|_| allocate_id(kind, db)).collect()
+}
+
+// The card whose Source is the given URL. Should several cards claim the same source, the
+// most recently modified one wins.
+pub fn sql_find_card_by_source(db: &rusqlite::Connection, source: &str) -> Result<Option<(u32, u64)>, Error> {
+    db.query_row("SELECT card_type, id FROM Cards WHERE source IS ?1 ORDER BY modified DESC LIMIT 1", params![source],
+                 |row| Ok((row.get::<usize, u32>(0)?, row.get::<usize, u64>(1)?)))
+        .optional()
+        .map_err(Error::DatabaseError)
+}
@@ -0,0 +1,94 @@
+use crate::cards::{self, Error};
+use crate::links;
+use crate::registry;
+
+// Bulk import of newline-delimited JSON, one card per line in any mix of types. Each card
+// gets its ID the same way as with POST /<type> (so cards re-imported from the same Source
+// replace the earlier import, even within the same batch). Every line succeeds or fails on
+// its own; cards that pass are written and indexed in a single transaction. The watcher
+// still sees the files appear but finds them indexed already.
+
+pub struct ImportResult {
+    pub line: usize,
+    pub result: Result<(u32, u64, bool), Error>, // Type code, ID and whether an existing card was replaced
+}
+
+impl ImportResult {
+    pub fn json(&self) -> serde_json::Value {
+        match &self.result {
+            Ok((code, id, replaced)) => serde_json::json!({
+                "line": self.line,
+                "card": links::qualified_id(*code, *id),
+                "status": if *replaced { "updated" } else { "created" },
+            }),
+            Err(e) => serde_json::json!({
+                "line": self.line,
+                "error": match e {
+                    Error::InvalidCard(e) | Error::CardExists(e) => e.clone(),
+                    e => e.to_string(),
+                },
+            }),
+        }
+    }
+}
+
+fn import_card(line: &str, db: &rusqlite::Connection) -> Result<(u32, u64, bool), Error> {
+
+    let mut card = match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(serde_json::Value::Object(card)) => card,
+        Ok(_) => return Err(Error::InvalidCard(String::from("Card must be a JSON object"))),
+        Err(e) => return Err(Error::InvalidCard(format!("Not valid JSON: {}", e))),
+    };
+    let kind = match card.get("Type").and_then(|typ| typ.as_str()) {
+        Some(typ) => registry::get().find(typ)
+            .ok_or_else(|| Error::InvalidCard(format!("Unknown card type '{}'", typ)))?,
+        None => return Err(Error::InvalidCard(String::from("Card has no Type"))),
+    };
+
+    let (id, replaces) = crate::prepare_new_card(kind, &mut card, db)?;
+    let previous = std::fs::read(cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?).ok();
+    let path = crate::save_card(kind, id, &serde_json::Value::Object(card), db)?;
+
+    // The index gets rolled back if the card can't be loaded, so put its file back the way
+    // it was as well rather than leave the watcher to find it.
+    let loaded = crate::remove_card_from_db(kind.sql_table(), kind.code(), id, db)
+        .and_then(|()| crate::load_card_into_db(kind, id, &path, db));
+    if loaded.is_err() {
+        let _ = match previous {
+            Some(previous) => std::fs::write(&path, previous),
+            None => std::fs::remove_file(&path),
+        };
+    }
+    loaded?;
+
+    Ok((kind.code(), id, replaces))
+}
+
+pub fn import_cards(input: &str, db: &rusqlite::Connection) -> Result<Vec<ImportResult>, Error> {
+
+    let _lock = crate::WRITE_LOCK.lock().unwrap();
+
+    crate::in_transaction(db, || {
+        let mut results = Vec::new();
+        for (index, line) in input.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // A card that fails half-way through being indexed mustn't leave anything behind.
+            db.execute("SAVEPOINT import_card", [])
+                .map_err(Error::DatabaseError)?;
+            let result = import_card(line, db);
+            if result.is_err() {
+                db.execute("ROLLBACK TO import_card", [])
+                    .map_err(Error::DatabaseError)?;
+            }
+            db.execute("RELEASE import_card", [])
+                .map_err(Error::DatabaseError)?;
+
+            results.push(ImportResult { line: index + 1, result });
+        }
+
+        Ok(results)
+    })
+}
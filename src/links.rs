@@ -0,0 +1,149 @@
+use std::collections::{HashSet, VecDeque};
+use rusqlite::params;
+use crate::cards::{CardType, Error};
+
+// Queries over the Links table. Links are stored by type code so these turn them back into
+// qualified IDs (type/id) for the API.
+
+pub const MAX_GRAPH_DEPTH: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+    Both,
+}
+
+pub struct Link {
+    pub role: String,
+    pub from: (u32, u64),
+    pub to: (u32, u64),
+    pub dangling: bool,
+}
+
+// Links that need fixing in the cards they come from.
+pub struct LinkReport {
+    pub dangling: Vec<Link>,
+    pub invalid_type: Vec<Link>,
+    pub self_links: Vec<Link>,
+}
+
+// What sql_card_graph() finds: every card reached along with its distance from the start
+// and all links that were followed.
+pub struct CardGraph {
+    pub cards: Vec<((u32, u64), u32)>,
+    pub links: Vec<Link>,
+}
+
+impl Link {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "from": qualified_id(self.from.0, self.from.1),
+            "role": self.role,
+            "to": qualified_id(self.to.0, self.to.1),
+            "dangling": self.dangling,
+        })
+    }
+}
+
+impl LinkReport {
+    pub fn is_empty(&self) -> bool {
+        self.dangling.is_empty() && self.invalid_type.is_empty() && self.self_links.is_empty()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        fn list(links: &[Link]) -> Vec<serde_json::Value> {
+            links.iter().map(|link| link.json()).collect()
+        }
+        serde_json::json!({
+            "dangling": list(&self.dangling),
+            "invalid_type": list(&self.invalid_type),
+            "self": list(&self.self_links),
+        })
+    }
+}
+
+pub fn qualified_id(code: u32, id: u64) -> String {
+    let typ = crate::registry::get().find_by_code(code)
+        .map(|kind| String::from(kind.typ_str()))
+        .unwrap_or_else(|| CardType::Invalid.to_string());
+    format!("{}/{}", typ, id)
+}
+
+fn sql_query_links<P: rusqlite::Params>(db: &rusqlite::Connection, condition: &str, params: P) -> Result<Vec<Link>, Error> {
+    let mut stmt = db.prepare_cached(&format!("SELECT role, from_type, from_id, to_type, to_id, dangling FROM Links WHERE {}", condition))
+        .map_err(Error::DatabaseError)?;
+    let rows = stmt.query_map(params, |row| Ok(Link {
+            role: row.get::<usize, Option<String>>(0)?.unwrap_or_default(),
+            from: (row.get(1)?, row.get(2)?),
+            to: (row.get(3)?, row.get(4)?),
+            dangling: row.get(5)?,
+        }))
+        .map_err(Error::DatabaseError)?;
+    rows.collect::<Result<Vec<Link>, _>>()
+        .map_err(Error::DatabaseError)
+}
+
+// Links of the given card in the given direction, optionally restricted to one role.
+pub fn sql_card_links(db: &rusqlite::Connection, code: u32, id: u64, direction: Direction, role: Option<&str>) -> Result<Vec<Link>, Error> {
+
+    let mut links = Vec::new();
+
+    if direction != Direction::Incoming {
+        links.append(&mut sql_query_links(db, "from_type IS ?1 AND from_id IS ?2 AND (?3 IS NULL OR role IS ?3)", params![code, id, role])?);
+    }
+    if direction != Direction::Outgoing {
+        links.append(&mut sql_query_links(db, "to_type IS ?1 AND to_id IS ?2 AND (?3 IS NULL OR role IS ?3)", params![code, id, role])?);
+    }
+
+    Ok(links)
+}
+
+// Find links whose target doesn't exist, whose target type isn't known (anymore) and links
+// from cards to themselves. Links to unknown types are only reported as such, not also as
+// dangling.
+pub fn sql_link_report(db: &rusqlite::Connection) -> Result<LinkReport, Error> {
+
+    let codes = crate::registry::get().kinds()
+        .map(|kind| kind.code().to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    Ok(LinkReport {
+        dangling: sql_query_links(db, &format!("dangling AND to_type IN ({})", codes), [])?,
+        invalid_type: sql_query_links(db, &format!("to_type NOT IN ({})", codes), [])?,
+        self_links: sql_query_links(db, "from_type IS to_type AND from_id IS to_id", [])?,
+    })
+}
+
+// Breadth-first walk of the link graph starting at the given card.
+pub fn sql_card_graph(db: &rusqlite::Connection, code: u32, id: u64, depth: u32, direction: Direction, role: Option<&str>)
+    -> Result<CardGraph, Error> {
+
+    let mut seen = HashSet::new();
+    let mut cards = Vec::new();
+    let mut links = Vec::new();
+    let mut seen_links = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    seen.insert((code, id));
+    queue.push_back(((code, id), 0));
+
+    while let Some((card, distance)) = queue.pop_front() {
+        if distance >= depth {
+            continue;
+        }
+        for link in sql_card_links(db, card.0, card.1, direction, role)? {
+            let other = if link.from == card { link.to } else { link.from };
+            if seen.insert(other) {
+                cards.push((other, distance + 1));
+                queue.push_back((other, distance + 1));
+            }
+            if seen_links.insert((link.role.clone(), link.from, link.to)) {
+                links.push(link);
+            }
+        }
+    }
+
+    Ok(CardGraph { cards, links })
+}
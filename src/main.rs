@@ -1,47 +1,79 @@
+use std::collections::HashMap;
 use std::ops::Deref;
-use std::path::{PathBuf};
-use std::sync::mpsc;
-use notify::{RecursiveMode, Watcher};
-use r2d2::Pool;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{OptionalExtension, params};
 use warp::Filter;
-use crate::cards::{Card, Project, Task, Status, Timelog, Book, Purchase, Metric, Word, Achievement, Note, Thought, Notebook};
+use crate::registry::CardKind;
 
 // API:
 // GET /<type>                  u64 list of cards of the given type
 // GET /<type>?prop=val...      u64 list of cards of the given type that have the given property values
-// GET /<type>?_where=q         u64 list of cards of the given type that match the given SQL query
+// GET /<type>?_where=q         u64 list of cards of the given type that match the given query (see query.rs)
+// GET /<type>?sort=p:desc&...  List parameters that work with all of the above and /cards: sort=p[:asc|desc],...,
+//                              limit=n, offset=n and fields=p,... (returns [{id, p...}] rows from the index)
+// GET /<type>?ids=1,2,3        JSON object with the contents of the given cards keyed by qualified ID (null if missing)
 // GET /<type>/count            u64 count of the number of cards of the given type
 // GET /<type>/<id>             JSON object containing the contents of the given card
 // GET /<type>/<str>            Same as by ID but tries to look up a card by the given fragment of its title
+// GET /<type>/<id>/links      Outgoing links of the given card as [{role, card}] (optionally ?role=r)
+// GET /<type>/<id>/backlinks  Incoming links of the given card as [{role, card}] (optionally ?role=r)
+// GET /<type>/<id>/graph      Cards and links reachable from the given card (?depth=1..5, direction=in|out|both, role=r)
+// GET /cards?prop=val...       Qualified IDs (type/id) of cards of any type that match; only common fields
+//                              (type, title, created, modified, source) plus tag, linked-to and _where
+// POST /cards/batch            JSON array of qualified IDs; same as ?ids= but for cards of any type
+// POST /<type>                 JSON card; writes a new card (with the ID given as "Id" or a newly allocated one);
+//                              a card without "Id" whose Source is already known to the index replaces that card
+// PUT /<type>/<id>             JSON card; creates or replaces the given card
+// PATCH /<type>/<id>           JSON merge patch (RFC 7396); updates the given card and bumps its Modified time
+// DELETE /<type>/<id>          Deletes the given card
+// POST /import                 Newline-delimited JSON cards of any type; writes them all in one go and returns
+//                              [{line, card, status}] or [{line, error}] for every line (see import.rs)
+// GET /export?format=f&...     All cards as NDJSON (default) or a tar of their files, optionally filtered like /cards
+//                              plus from/to on the creation date; format=sqlite gives a snapshot of the index
+// GET /search?q=text           Cards of any type matching the text, best first, as [{card, title, snippet, score}]
+//                              (optionally type=t and limit=n; q can use SQLite FTS5 query syntax, see search.rs)
+// GET /_types                  JSON array of all card types (built-in and from schema files)
+// GET /_links                  JSON object listing dangling links, links to invalid types and self-links
+// GET /_errors                 Cards that could not be indexed as [{card, path, error, time}] (see quarantine.rs)
+// POST /_ids?type=t&count=n    Allocates n (default 1) new IDs for cards of type t (see ids.rs)
+// GET /_sources?source=url     Qualified ID of the card with the given Source
 //
-// Choices:
-// - Exposing SQL directly; gives access to full-fledged query language at the expense of tying us to implementation details
+// GET responses carry an ETag for the card file. PUT, PATCH and DELETE take an If-Match
+// header and fail with 412 if the card has changed since.
+//
+// Errors come back as RFC 7807 problem details ({type, title, status, detail} with content
+// type application/problem+json), with 503 if the database is busy.
 //
-// Missing
-// - Looking up card(s) by tag
-// - Looking up card(s) by links
-// - Looking up cards of mixed types
+// Choices:
+// - Own small query language compiled to SQL rather than exposing SQL directly; keeps clients away from implementation details
 //
 // Explore
 // - Using an external SQL DB on the system for storage such that the DB is visible/accessible
 //   to everyone without going through gulper_index
 
-////TODO: store cards.sqlite in a place where other tools can access it
-
+mod batch;
 mod cards;
+mod config;
+mod export;
+mod ids;
+mod import;
+mod links;
+mod migrations;
+mod query;
+mod quarantine;
+mod registry;
+mod schemas;
+mod search;
+mod watcher;
 
 mod report {
     use std::process::Command;
     use std::sync::mpsc;
     use std::thread;
     use chrono::Utc;
-
-    const R_BIN_PATH: &str = "M:/R/4.1.3/bin/x64";
-    const RSTUDIO_BIN_PATH: &str = "C:/Program Files/RStudio/bin/quarto/bin";
-    const DAILY_REPORT_RMD_PATH: &str = "C:/Dropbox/Data/R/DailyReport.Rmd";
-    const DAILY_REPORT_HTML_PATH: &str = "C:/Dropbox/Data/R/DailyReport.html";
-    const DAILY_REPORT_FOLDER_PATH: &str = "C:/Dropbox/Data/Reports/Daily";
+    use crate::config::ReportConfig;
 
     #[derive(PartialEq)]
     pub enum ReportThreadCommand {
@@ -54,7 +86,7 @@ mod report {
         pub channel: mpsc::Sender<ReportThreadCommand>,
     }
 
-    pub fn spawn_thread() -> ReportThread {
+    pub fn spawn_thread(config: ReportConfig) -> ReportThread {
 
         let (tx, rx) = mpsc::channel();
         let handle = thread::spawn(move || {
@@ -64,13 +96,17 @@ mod report {
                     break
                 }
                 else if command == ReportThreadCommand::Refresh {
+                    if !config.enabled {
+                        continue
+                    }
                     ////TODO: consume all pending Refresh commands before running a refresh
-                    let output = Command::new(format!("{}/Rscript.exe", R_BIN_PATH))
+                    let rscript = config.r_bin_path.join(if cfg!(windows) { "Rscript.exe" } else { "Rscript" });
+                    let output = Command::new(&rscript)
                         .arg("-e")
-                        .arg(format!("library(rmarkdown); rmarkdown::render('{}', 'html_document')", DAILY_REPORT_RMD_PATH))
-                        .env("PATH", RSTUDIO_BIN_PATH)
+                        .arg(format!("library(rmarkdown); rmarkdown::render('{}', 'html_document')", config.rmd_path.display()))
+                        .env("PATH", &config.quarto_bin_path)
                         .output()
-                        .expect("Failed to run RScript.exe");
+                        .expect("Failed to run Rscript");
 
                     println!("Updated DailyReport.");
                     if !output.status.success() {
@@ -80,7 +116,7 @@ mod report {
                         // the current date.
                         let now = Utc::now();
                         let dmy = now.format("%Y-%m-%d");
-                        std::fs::copy(DAILY_REPORT_HTML_PATH, format!("{}/{}.html", DAILY_REPORT_FOLDER_PATH, dmy));
+                        std::fs::copy(&config.html_path, config.folder_path.join(format!("{}.html", dmy)));
                     }
                 }
             }
@@ -102,256 +138,423 @@ mod report {
     }
 }
 
-fn remove_card_from_db<T: Card>(id: u64, db: &rusqlite::Connection, include_incoming_links: bool) -> Result<(), cards::Error> {
+// Incoming links belong to the cards they come from so they stay in the index; they are
+// only marked dangling until the card comes back.
+fn remove_card_from_db(table: &str, code: u32, id: u64, db: &rusqlite::Connection) -> Result<(), cards::Error> {
 
-    let mut del_card_stmt = db.prepare(&format!("DELETE FROM {} WHERE id IS {}", T::sql_table(), id))
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+    let mut del_card_stmt = db.prepare(&format!("DELETE FROM {} WHERE id IS {}", table, id))
+        .map_err(cards::Error::DatabaseError)?;
 
-    let mut del_links_stmt = if include_incoming_links {
-        db.prepare(&format!("DELETE FROM Links WHERE (from_type IS {} AND from_id IS {}) OR (to_type IS {} AND to_id IS {})",
-                            T::typ() as u32, id,
-                            T::typ() as u32, id))
-    } else {
-        db.prepare(&format!("DELETE FROM Links WHERE from_type IS {} AND from_id IS {}",
-                            T::typ() as u32, id))
-    }
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+    let mut del_links_stmt = db.prepare(&format!("DELETE FROM Links WHERE from_type IS {} AND from_id IS {}", code, id))
+        .map_err(cards::Error::DatabaseError)?;
+
+    let mut mark_links_stmt = db.prepare(&format!("UPDATE Links SET dangling = 1 WHERE to_type IS {} AND to_id IS {}", code, id))
+        .map_err(cards::Error::DatabaseError)?;
+
+    let mut del_tags_stmt = db.prepare(&format!("DELETE FROM Taggings WHERE card_type IS {} AND card_id IS {}", code, id))
+        .map_err(cards::Error::DatabaseError)?;
 
-    let mut del_tags_stmt = db.prepare(&format!("DELETE FROM Taggings WHERE card_type IS {} AND card_id IS {}", T::typ() as u32, id))
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+    let mut del_file_stmt = db.prepare(&format!("DELETE FROM CardFiles WHERE card_type IS {} AND card_id IS {}", code, id))
+        .map_err(cards::Error::DatabaseError)?;
 
     del_card_stmt.execute([])
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+        .map_err(cards::Error::DatabaseError)?;
     del_links_stmt.execute([])
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+        .map_err(cards::Error::DatabaseError)?;
+    mark_links_stmt.execute([])
+        .map_err(cards::Error::DatabaseError)?;
     del_tags_stmt.execute([])
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+        .map_err(cards::Error::DatabaseError)?;
+    del_file_stmt.execute([])
+        .map_err(cards::Error::DatabaseError)?;
 
+    quarantine::sql_clear_error(db, code, id)?;
+    search::sql_remove_card_text(db, code, id)
+}
+
+fn write_card_file_stamp(typ: &str, code: u32, id: u64, path: &Path, hash: i64, db: &rusqlite::Connection) -> Result<(), cards::Error> {
+    let (mtime, size) = cards::get_card_file_stamp(path)?;
+    db.execute("INSERT OR REPLACE INTO CardFiles (card_type, card_id, mtime, size, hash, path) VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+               params![code, id, mtime, size, hash, cards::get_relative_card_path(typ, path)])
+        .map_err(cards::Error::DatabaseError)?;
     Ok(())
 }
 
-fn prepare_card_write_stmts<T: Card>(db: &rusqlite::Connection)
-    -> Result<(rusqlite::Statement, rusqlite::Statement, rusqlite::Statement, rusqlite::Statement, rusqlite::Statement), rusqlite::Error> {
-    Ok((db.prepare(T::sql_write_stmt())?,
-        db.prepare("INSERT INTO Links (role, from_type, from_id, to_type, to_id) VALUES(?1, ?2, ?3, ?4, ?5)")?,
-        db.prepare("INSERT OR IGNORE INTO Tags VALUES(?1)")?,
-        db.prepare("SELECT rowid FROM Tags WHERE name IS ?1")?,
-        db.prepare("INSERT INTO Taggings (tag_id, card_type, card_id) VALUES(?1, ?2, ?3)")?))
+// Whether the index already has the given card file as it is on disk, e.g. because it was
+// written through the API. Saves the watcher from indexing it all over again.
+fn is_card_file_indexed(kind: &dyn CardKind, id: u64, path: &Path, db: &rusqlite::Connection) -> Result<bool, cards::Error> {
+    let (mtime, size) = cards::get_card_file_stamp(path)?;
+    let indexed = db.query_row("SELECT 1 FROM CardFiles WHERE card_type IS ?1 AND card_id IS ?2 AND mtime IS ?3 AND size IS ?4 AND path IS ?5",
+                               params![kind.code(), id, mtime, size, cards::get_relative_card_path(kind.typ_str(), path)], |_| Ok(()))
+        .optional()
+        .map_err(cards::Error::DatabaseError)?;
+    Ok(indexed.is_some())
 }
 
-fn load_card_into_db<T: Card>(id: u64, db: &rusqlite::Connection) -> Result<(), cards::Error> {
+fn load_card_into_db(kind: &dyn CardKind, id: u64, path: &Path, db: &rusqlite::Connection) -> Result<(), cards::Error> {
+    kind.sql_load(db, id, path)?;
+    let contents = std::fs::read(path)
+        .map_err(|err| cards::Error::CantAccessCard(path.to_path_buf(), err))?;
+    let json: serde_json::Value = serde_json::from_slice(&contents)
+        .map_err(|err| cards::Error::CantReadFormatOfCard(path.to_path_buf(), err))?;
+    search::sql_write_card_text(db, kind.code(), id, &json)?;
+    write_card_file_stamp(kind.typ_str(), kind.code(), id, path, cards::hash_contents(&contents), db)?;
+    db.execute("UPDATE Links SET dangling = 0 WHERE to_type IS ?1 AND to_id IS ?2", params![kind.code(), id])
+        .map_err(cards::Error::DatabaseError)?;
+    Ok(())
+}
+
+// IDs of the cards that sync_cards_with_db() had to touch.
+#[derive(Default)]
+struct SyncReport {
+    added: Vec<u64>,
+    updated: Vec<u64>,
+    removed: Vec<u64>,
+    skipped: Vec<u64>,
+}
 
-    let (mut sql, mut link, mut tag_insert, mut tag_lookup, mut tagging_insert) = prepare_card_write_stmts::<T>(db)
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+impl SyncReport {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty() && self.skipped.is_empty()
+    }
+}
 
-    let card = T::load(id)?;
-    card.sql_write(&mut sql)?;
-    card.sql_write_links(&mut link)?;
-    card.sql_write_tags(&mut tag_insert, &mut tag_lookup, &mut tagging_insert)?;
+// Card writes coming in through the API are serialized so that ID allocation and checks
+// for whether a card exists don't race each other.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
 
-    Ok(())
+fn in_transaction<T, F: FnOnce() -> Result<T, cards::Error>>(db: &rusqlite::Connection, f: F) -> Result<T, cards::Error> {
+    db.execute("BEGIN TRANSACTION", [])
+        .map_err(cards::Error::DatabaseError)?;
+    let result = f().and_then(|value| {
+        db.execute("COMMIT", [])
+            .map(|_| value)
+            .map_err(cards::Error::DatabaseError)
+    });
+    // A failed COMMIT can leave the transaction open, too.
+    if result.is_err() && !db.is_autocommit() {
+        db.execute("ROLLBACK", [])
+            .map_err(cards::Error::DatabaseError)?;
+    }
+    result
 }
 
-fn load_all_cards_into_db<T: Card>(db: &rusqlite::Connection) -> Result<(), cards::Error> {
+fn card_exists(kind: &dyn CardKind, id: u64, db: &rusqlite::Connection) -> Result<bool, cards::Error> {
+    Ok(cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?.exists())
+}
 
-    let (mut sql, mut link, mut tag_insert, mut tag_lookup, mut tagging_insert) = prepare_card_write_stmts::<T>(db)
-        .map_err(|err| cards::Error::DatabaseError(err.to_string()))?;
+fn timestamp_now() -> String {
+    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
 
-    db.execute("BEGIN TRANSACTION", [])
-        .expect("Cannot begin transaction");
+// Fill in the fields every card file has so that clients only have to send the fields
+// specific to the card type.
+fn fill_card_fields(kind: &dyn CardKind, id: u64, card: &mut serde_json::Map<String, serde_json::Value>) {
+    let now = timestamp_now();
+    card.insert(String::from("Id"), serde_json::json!(id));
+    card.insert(String::from("Type"), serde_json::json!(kind.typ_str()));
+    card.entry("Created").or_insert_with(|| serde_json::json!(now));
+    card.entry("Modified").or_insert_with(|| serde_json::json!(now));
+}
 
-    for id in T::list() {
-        let card = T::load(id)?;
-        card.sql_write(&mut sql)?;
-        card.sql_write_links(&mut link)?;
-        card.sql_write_tags(&mut tag_insert, &mut tag_lookup, &mut tagging_insert)?;
+// Pick the ID for a new card: the "Id" it comes with, the card that was imported from the
+// same Source before (so re-imports update rather than duplicate) or a newly allocated one.
+// Fills in the common fields and returns the ID along with whether an earlier import gets
+// replaced.
+fn prepare_new_card(kind: &dyn CardKind, card: &mut serde_json::Map<String, serde_json::Value>, db: &rusqlite::Connection) -> Result<(u64, bool), cards::Error> {
+
+    let imported = match (card.get("Id"), card.get("Source").and_then(|source| source.as_str())) {
+        (None, Some(source)) => ids::sql_find_card_by_source(db, source)?,
+        _ => None,
+    };
+
+    let id = match (card.get("Id"), imported) {
+        (None, Some((code, id))) if code == kind.code() => id,
+        (None, Some((code, id))) => return Err(cards::Error::CardExists(format!("Source already imported as {}", links::qualified_id(code, id)))),
+        (None, None) => ids::allocate_id(kind, db)?,
+        (Some(id), _) => id.as_u64()
+            .ok_or_else(|| cards::Error::InvalidCard(String::from("Id must be a positive integer")))?,
+    };
+
+    let exists = card_exists(kind, id, db)?;
+    if exists && imported.is_none() {
+        return Err(cards::Error::CardExists(format!("Card already exists: {}/{}", kind.typ_str(), id)));
+    }
+    if exists {
+        // Keep the time the card was first imported.
+        let previous = kind.json(db, id)?;
+        if let Some(created) = serde_json::from_str::<serde_json::Value>(&previous).ok().and_then(|previous| previous.get("Created").cloned()) {
+            card.entry("Created").or_insert(created);
+        }
     }
 
-    db.execute("COMMIT", [])
-        .expect("Cannot commit transaction");
+    fill_card_fields(kind, id, card);
+    Ok((id, exists))
+}
 
-    Ok(())
+// Fail unless the card file matches one of the ETags in the given If-Match header. As per
+// RFC 7232, weak ETags never match and "*" only matches if the card exists.
+fn check_if_match(path: &Path, if_match: Option<&str>) -> Result<(), cards::Error> {
+    let if_match = match if_match {
+        Some(if_match) => if_match,
+        None => return Ok(()),
+    };
+    let etag = std::fs::read(path).ok()
+        .map(|contents| cards::get_card_etag(&contents));
+    let matches = match &etag {
+        Some(etag) => if_match.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || tag == etag),
+        None => false,
+    };
+    if matches {
+        Ok(())
+    }
+    else {
+        Err(cards::Error::PreconditionFailed(match etag {
+            Some(etag) => format!("card has been changed (ETag is now {})", etag),
+            None => String::from("card does not exist"),
+        }))
+    }
 }
 
-fn populate_db_from_scratch(db: &rusqlite::Connection) -> Result<(), cards::Error> {
-    load_all_cards_into_db::<Project>(db)?;
-    load_all_cards_into_db::<Task>(db)?;
-    load_all_cards_into_db::<Status>(db)?;
-    load_all_cards_into_db::<Timelog>(db)?;
-    load_all_cards_into_db::<Purchase>(db)?;
-    load_all_cards_into_db::<Metric>(db)?;
-    load_all_cards_into_db::<Word>(db)?;
-    load_all_cards_into_db::<Note>(db)?;
-    load_all_cards_into_db::<Thought>(db)?;
-    load_all_cards_into_db::<Achievement>(db)?;
-    load_all_cards_into_db::<Notebook>(db)?;
-    load_all_cards_into_db::<Book>(db)
+// Check the given card against its type and write it to disk. Doesn't touch the index.
+fn save_card(kind: &dyn CardKind, id: u64, contents: &serde_json::Value, db: &rusqlite::Connection) -> Result<PathBuf, cards::Error> {
+    let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?;
+    cards::write_card_file(&path, contents, |temp_path| {
+        kind.validate(id, temp_path).map_err(|err| match err {
+            cards::Error::InvalidCard(e) => cards::Error::InvalidCard(e),
+            // The file is only a temporary one so leave it out.
+            cards::Error::CantReadProperty(e) => cards::Error::InvalidCard(e.to_string()),
+            cards::Error::CantReadFormatOfCard(_, e) => cards::Error::InvalidCard(e.to_string()),
+            e => cards::Error::InvalidCard(e.to_string()),
+        })
+    })?;
+    Ok(path)
 }
 
-fn init_db(db: &rusqlite::Connection) -> Result<(), cards::Error> {
+// Write the given card to disk and bring it into the index right away instead of waiting
+// for the watcher so that the card can be read back immediately. Returns the card's new ETag.
+fn write_card(kind: &dyn CardKind, id: u64, contents: &serde_json::Value, db: &rusqlite::Connection) -> Result<String, cards::Error> {
+    let path = save_card(kind, id, contents, db)?;
+    in_transaction(db, || {
+        remove_card_from_db(kind.sql_table(), kind.code(), id, db)?;
+        load_card_into_db(kind, id, &path, db)
+    })?;
+    let contents = std::fs::read(&path)
+        .map_err(|err| cards::Error::CantAccessCard(path.clone(), err))?;
+    Ok(cards::get_card_etag(&contents))
+}
 
-    // For now, rebuild from scratch every time.
-    let stmt = format!(r#"
-        BEGIN;
-        DROP TABLE IF EXISTS Tags;
-        DROP TABLE IF EXISTS Taggings;
-        DROP TABLE IF EXISTS Links;
-        CREATE TABLE IF NOT EXISTS Tags (
-            name VARCHAR PRIMARY KEY UNIQUE
-        );
-        CREATE TABLE IF NOT EXISTS Taggings (
-            tag_id INTEGER,
-            card_type INTEGER,
-            card_id INTEGER
-        );
-        CREATE TABLE IF NOT EXISTS Links (
-            role VARCHAR,
-            from_type INTEGER,
-            from_id INTEGER,
-            to_type INTEGER,
-            to_id INTEGER
-        );
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        {}
-        COMMIT;"#,
-                       Project::sql_schema(),
-                       Task::sql_schema(),
-                       Status::sql_schema(),
-                       Timelog::sql_schema(),
-                       Purchase::sql_schema(),
-                       Metric::sql_schema(),
-                       Word::sql_schema(),
-                       Note::sql_schema(),
-                       Thought::sql_schema(),
-                       Achievement::sql_schema(),
-                       Notebook::sql_schema(),
-                       Book::sql_schema());
-
-    db.execute_batch(&stmt,)
-        .map_err(|err| { cards::Error::DatabaseError(err.to_string())})?;
-
-    populate_db_from_scratch(db)
+fn delete_card(kind: &dyn CardKind, id: u64, if_match: Option<&str>, db: &rusqlite::Connection) -> Result<(), cards::Error> {
+    let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?;
+    if !path.exists() {
+        return Err(cards::Error::CantFindCard(format!("{}/{}", kind.typ_str(), id)));
+    }
+    check_if_match(&path, if_match)?;
+    std::fs::remove_file(&path)
+        .map_err(|err| cards::Error::CantAccessCard(path.clone(), err))?;
+    in_transaction(db, || remove_card_from_db(kind.sql_table(), kind.code(), id, db))
 }
 
-struct FileWatcher(notify::RecommendedWatcher);
+// Bring the index for cards of the given type in line with what is on disk. Only cards
+// whose files were added, removed or changed since the last run (according to CardFiles)
+// are touched. Needs to run inside a transaction.
+fn sync_cards_with_db(kind: &dyn CardKind, db: &rusqlite::Connection) -> Result<SyncReport, cards::Error> {
+
+    let (typ, code, table) = (kind.typ_str(), kind.code(), kind.sql_table());
+
+    let mut known = HashMap::new();
+    {
+        let mut stmt = db.prepare("SELECT card_id, mtime, size, hash, path FROM CardFiles WHERE card_type IS ?1")
+            .map_err(cards::Error::DatabaseError)?;
+        let mut rows = stmt.query(params![code])
+            .map_err(cards::Error::DatabaseError)?;
+        while let Some(row) = rows.next().map_err(cards::Error::DatabaseError)? {
+            let id = row.get::<usize, u64>(0).map_err(cards::Error::DatabaseError)?;
+            let stamp = (row.get::<usize, i64>(1).map_err(cards::Error::DatabaseError)?,
+                         row.get::<usize, i64>(2).map_err(cards::Error::DatabaseError)?,
+                         row.get::<usize, i64>(3).map_err(cards::Error::DatabaseError)?,
+                         row.get::<usize, Option<String>>(4).map_err(cards::Error::DatabaseError)?);
+            known.insert(id, stamp);
+        }
+    }
 
-fn init_watcher<T: Card>(db: Pool<SqliteConnectionManager>, report_thread: mpsc::Sender<report::ReportThreadCommand>) -> FileWatcher {
+    // Before touching the index. If the folder can't be read, we can't tell what's gone.
+    let files = cards::list_card_files(typ)?;
 
-    let mut watcher = notify::recommended_watcher(move |res: Result<notify::Event, notify::Error>| {
+    let mut report = SyncReport::default();
 
-        fn is_json_file(path: &PathBuf) -> bool {
-            if let Some(ext) = path.extension() {
-                ext == "json"
-            }
-            else {
-                false
-            }
-        }
+    // Quarantined cards are tried again below.
+    quarantine::sql_clear_errors(db, code)?;
 
-        fn add_card<T: Card>(name: String, db: Pool<SqliteConnectionManager>, report_thread: mpsc::Sender<report::ReportThreadCommand>) {
-            if let Ok(id) = name.parse::<u64>() {
-                let db = db.get()
-                    .expect("Cannot get DB connection");
-                db.execute("BEGIN TRANSACTION", [])
-                    .expect("Cannot begin transaction");
-                match load_card_into_db::<T>(id, &db) {
-                    Err(e) => println!("Cannot write card '{}/{}': {:?}", T::typ_str(), name, e),
-                    _ => (),
+    for (id, path) in files {
+        match known.remove(&id) {
+            None => {
+                match quarantine::load_or_quarantine(kind, id, &path, db)? {
+                    true => report.added.push(id),
+                    false => report.skipped.push(id),
+                }
+            },
+            Some((mtime, size, hash, known_path)) => {
+                let moved = known_path.as_deref() != Some(cards::get_relative_card_path(typ, &path).as_str());
+                // A file we can't even read is quarantined like any other broken card.
+                let new_hash = match cards::get_card_file_stamp(&path) {
+                    Ok(stamp) if !moved && stamp == (mtime, size) => continue,
+                    Ok(_) => cards::get_card_file_hash(&path),
+                    Err(e) => Err(e),
+                };
+                let new_hash = match new_hash {
+                    Ok(new_hash) => new_hash,
+                    Err(e) => {
+                        quarantine::quarantine_card(kind, id, &path, &e, db)?;
+                        report.skipped.push(id);
+                        continue
+                    },
+                };
+                if new_hash == hash {
+                    // Touched or moved but not changed.
+                    write_card_file_stamp(typ, code, id, &path, hash, db)?;
+                    continue
+                }
+                remove_card_from_db(table, code, id, db)?;
+                match quarantine::load_or_quarantine(kind, id, &path, db)? {
+                    true => report.updated.push(id),
+                    false => report.skipped.push(id),
                 }
-                db.execute("COMMIT", [])
-                    .expect("Cannot commit transaction");
-                report::update_report(&report_thread);
             }
         }
+    }
 
-        fn remove_card<T: Card>(name: String, db: Pool<SqliteConnectionManager>, report_thread: mpsc::Sender<report::ReportThreadCommand>, include_incoming_links: bool) {
-            if let Ok(id) = name.parse::<u64>() {
-                let db = db.get()
-                    .expect("Cannot get DB connection");
-                db.execute("BEGIN TRANSACTION", [])
-                    .expect("Cannot begin transaction");
-                match remove_card_from_db::<T>(id, &db, include_incoming_links) {
-                    Err(e) => println!("Cannot remove card '{}/{}': {:?}", T::typ_str(), name, e),
-                    _ => (),
-                }
-                db.execute("COMMIT", [])
-                    .expect("Cannot commit transaction");
-                report::update_report(&report_thread);
-            }
+    // Whatever is left has disappeared from disk.
+    for id in known.keys() {
+        remove_card_from_db(table, code, *id, db)?;
+        report.removed.push(*id);
+    }
+
+    Ok(report)
+}
+
+fn sync_db_with_card_files(db: &rusqlite::Connection) -> Result<(), cards::Error> {
+    for kind in registry::get().kinds() {
+        let report = match in_transaction(db, || sync_cards_with_db(kind, db)) {
+            Ok(report) => report,
+            Err(e @ cards::Error::CantReadCardFolder(..)) => {
+                println!("   {}: skipped, {}", kind.typ_str(), e);
+                continue
+            },
+            Err(e) => return Err(e),
+        };
+        if !report.is_empty() {
+            println!("   {}: {} added, {} updated, {} removed, {} skipped",
+                     kind.typ_str(), report.added.len(), report.updated.len(), report.removed.len(), report.skipped.len());
         }
+    }
+    Ok(())
+}
 
-        fn update_card<T: Card>(name: String, db: Pool<SqliteConnectionManager>, report_thread: mpsc::Sender<report::ReportThreadCommand>) {
-            if let Ok(id) = name.parse::<u64>() {
-                let db = db.get()
-                    .expect("Cannot get DB connection");
-                db.execute("BEGIN TRANSACTION", [])
-                    .expect("Cannot begin transaction");
-                match remove_card_from_db::<T>(id, &db, false) {
-                    Err(e) => println!("Cannot remove card '{}/{}': {:?}", T::typ_str(), name, e),
-                    _ => (),
-                }
-                match load_card_into_db::<T>(id, &db) {
-                    Err(e) => println!("Cannot write card '{}/{}': {:?}", T::typ_str(), name, e),
-                    _ => (),
-                }
-                db.execute("COMMIT", [])
-                    .expect("Cannot commit transaction");
-                report::update_report(&report_thread);
-            }
+fn init_db(db: &rusqlite::Connection) -> Result<(), cards::Error> {
+
+    // The index persists across runs. Bring its schema up to date and then only pick up
+    // the differences to what is on disk. The Cards view references all card tables so
+    // get it out of the way while those change.
+    db.execute_batch("DROP VIEW IF EXISTS Cards;")
+        .map_err(cards::Error::DatabaseError)?;
+    migrations::migrate(db)?;
+    schemas::sync_schema_tables(db, registry::get().schemas())?;
+    registry::get().sql_create_cards_view(db)?;
+
+    sync_db_with_card_files(db)
+}
+
+// Print the link report for the check-links command. Returns the exit code.
+fn check_links(db: &rusqlite::Connection) -> i32 {
+
+    let report = match links::sql_link_report(db) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("Cannot check links: {}", err);
+            return 1;
         }
+    };
 
-        match res {
-            Ok(event) => {
-                match event.kind {
-                    notify::EventKind::Create(_) => {
-                        for path in event.paths.iter().filter(|p| is_json_file(p)) {
-                            println!("Added card {}", path.to_str().unwrap());
-                            if let Some(name) = path.file_stem() {
-                                add_card::<T>(String::from(name.to_str().unwrap()), db.clone(), report_thread.clone());
-                            };
-                        }
-                    },
-                    notify::EventKind::Remove(_) => {
-                        for path in event.paths.iter().filter(|p| is_json_file(p)) {
-                            println!("Removed card {}", path.to_str().unwrap());
-                            if let Some(name) = path.file_stem() {
-                                ////FIXME: *If* the file comes *back* we have destroyed all incoming links and they are gone.
-                                ////       (should we leave incoming links in the DB?)
-                                remove_card::<T>(String::from(name.to_str().unwrap()), db.clone(), report_thread.clone(), true);
-                            };
-                        }
-                    },
-                    notify::EventKind::Modify(_) => {
-                        ////FIXME: Getting two notifications for every single change.
-                        for path in event.paths.iter().filter(|p| is_json_file(p)) {
-                            println!("Modified card {}", path.to_str().unwrap());
-                            if let Some(name) = path.file_stem() {
-                                update_card::<T>(String::from(name.to_str().unwrap()), db.clone(), report_thread.clone());
-                            };
-                        }
-                    },
-                    _ => {}, // Ignore
-                }
-            }
-            Err(e) => println!("FSWatcher error happened: {}", e.to_string()),
+    for (problem, list) in [("dangling", &report.dangling), ("invalid type", &report.invalid_type), ("self-link", &report.self_links)] {
+        for link in list.iter() {
+            println!("{:<14}{} -[{}]-> {}", problem,
+                     links::qualified_id(link.from.0, link.from.1), link.role, links::qualified_id(link.to.0, link.to.1));
         }
-    })
-        .expect("Cannot create file system watcher");
+    }
+
+    println!("{} dangling, {} to invalid types, {} self-links",
+             report.dangling.len(), report.invalid_type.len(), report.self_links.len());
+
+    if report.is_empty() { 0 } else { 1 }
+}
+
+// Run the validate command. Returns the exit code.
+fn validate_cards() -> i32 {
+
+    let (count, invalid) = match quarantine::validate_card_files() {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Cannot validate cards: {}", err);
+            return 1;
+        }
+    };
+    for file in invalid.iter() {
+        println!("{:<24}{} ({})", format!("{}/{}", file.kind.typ_str(), file.id), file.error.reason(), file.path.display());
+    }
+
+    println!("{} cards, {} invalid", count, invalid.len());
+
+    if invalid.is_empty() { 0 } else { 1 }
+}
+
+// Run the export command. Returns the exit code.
+fn export_cards(db: &rusqlite::Connection, options: &HashMap<String, String>) -> i32 {
 
-    watcher.watch(T::path().as_path(), RecursiveMode::NonRecursive)
-        .expect("Cannot watch card directory");
+    let output = match options.get("output") {
+        Some(output) => PathBuf::from(output),
+        None => {
+            eprintln!("export needs --output <path>");
+            return 2;
+        }
+    };
+    let format = match export::Format::parse(options.get("format").map(|s| s.as_str()).unwrap_or("ndjson")) {
+        Ok(format) => format,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+    let mut filters = HashMap::new();
+    for (option, filter) in [("type", "type"), ("where", "_where"), ("from", "from"), ("to", "to")] {
+        if let Some(value) = options.get(option) {
+            filters.insert(String::from(filter), value.clone());
+        }
+    }
 
-    FileWatcher(watcher)
+    // Snapshots can go straight to the output file.
+    let result = if format == export::Format::Sqlite && filters.is_empty() {
+        export::write_snapshot(db, &output)
+    }
+    else {
+        export::Export::prepare(db, format, &filters)
+            .and_then(|export| {
+                let file = std::fs::File::create(&output)
+                    .map_err(cards::Error::CantWriteExport)?;
+                export.write(db, &mut std::io::BufWriter::new(file))
+            })
+    };
+
+    match result {
+        Ok(()) => {
+            println!("Exported to {}", output.display());
+            0
+        },
+        Err(err) => {
+            eprintln!("Cannot export: {}", err);
+            1
+        }
+    }
 }
 
 mod filters {
@@ -360,39 +563,226 @@ mod filters {
     use r2d2_sqlite::SqliteConnectionManager;
     use super::handlers;
     use warp::Filter;
-    use crate::Card;
+    use crate::links;
+    use crate::registry::{self, CardKind};
+
+    const MAX_CARD_SIZE: u64 = 16 * 1024 * 1024;
+    const MAX_IMPORT_SIZE: u64 = 256 * 1024 * 1024;
+
+    pub fn api(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        types()
+            .or(link_report(db.clone()))
+            .or(index_errors(db.clone()))
+            .or(allocate_ids(db.clone()))
+            .or(find_source(db.clone()))
+            .or(import(db.clone()))
+            .or(export(db.clone()))
+            .or(search(db.clone()))
+            .or(cards(db.clone()))
+            .or(cards_batch(db.clone()))
+            .or(count(db.clone()))
+            .or(links(db.clone()))
+            .or(graph(db.clone()))
+            .or(list(db.clone()))
+            .or(get(db.clone()))
+            .or(create(db.clone()))
+            .or(replace(db.clone()))
+            .or(patch(db.clone()))
+            .or(delete(db.clone()))
+    }
+
+    pub fn types() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("_types")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and_then(handlers::types)
+    }
+
+    pub fn link_report(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("_links")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_db(db))
+            .and_then(handlers::link_report)
+    }
 
-    pub fn cards<T: Card>(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        count::<T>(db.clone())
-            .or(list::<T>(db.clone()))
-            .or(get::<T>(db.clone()))
+    pub fn index_errors(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("_errors")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_db(db))
+            .and_then(handlers::index_errors)
     }
 
-    pub fn count<T: Card>(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path(T::typ_str())
+    pub fn allocate_ids(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("_ids")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::allocate_ids)
+    }
+
+    pub fn find_source(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("_sources")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::find_source)
+    }
+
+    pub fn import(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("import")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+            .and(warp::body::bytes())
+            .and(with_db(db))
+            .and_then(handlers::import)
+    }
+
+    pub fn export(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("export")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::export)
+    }
+
+    pub fn search(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("search")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::search)
+    }
+
+    pub fn cards(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("cards")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::cards)
+    }
+
+    pub fn cards_batch(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("cards")
+            .and(warp::path("batch"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(json_body())
+            .and(with_db(db))
+            .and_then(handlers::cards_batch)
+    }
+
+    pub fn count(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
             .and(warp::path("count"))
             .and(warp::path::end())
             .and(warp::get())
             .and(with_db(db))
-            .and_then(handlers::count::<T>)
+            .and_then(handlers::count)
+    }
+
+    pub fn links(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let direction = warp::path("links").map(|| links::Direction::Outgoing)
+            .or(warp::path("backlinks").map(|| links::Direction::Incoming))
+            .unify();
+        with_kind()
+            .and(warp::path::param())
+            .and(direction)
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::links)
+    }
+
+    pub fn graph(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
+            .and(warp::path::param())
+            .and(warp::path("graph"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_db(db))
+            .and_then(handlers::graph)
     }
 
-    pub fn list<T: Card>(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path(T::typ_str())
+    pub fn list(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
             .and(warp::path::end())
             .and(warp::get())
             .and(warp::query::<HashMap<String, String>>())
             .and(with_db(db))
-            .and_then(handlers::list::<T>)
+            .and_then(handlers::list)
     }
 
-    pub fn get<T: Card>(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path(T::typ_str())
+    pub fn get(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
             .and(warp::path::param())
             .and(warp::path::end())
             .and(warp::get())
             .and(with_db(db))
-            .and_then(handlers::get::<T>)
+            .and_then(handlers::get)
+    }
+
+    pub fn create(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(json_body())
+            .and(with_db(db))
+            .and_then(handlers::create)
+    }
+
+    pub fn replace(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::put())
+            .and(warp::header::optional::<String>("if-match"))
+            .and(json_body())
+            .and(with_db(db))
+            .and_then(handlers::replace)
+    }
+
+    pub fn patch(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::patch())
+            .and(warp::header::optional::<String>("if-match"))
+            .and(json_body())
+            .and(with_db(db))
+            .and_then(handlers::patch)
+    }
+
+    pub fn delete(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_kind()
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(warp::header::optional::<String>("if-match"))
+            .and(with_db(db))
+            .and_then(handlers::delete)
+    }
+
+    fn json_body() -> impl Filter<Extract = (serde_json::Value,), Error = warp::Rejection> + Clone {
+        warp::body::content_length_limit(MAX_CARD_SIZE)
+            .and(warp::body::json())
+    }
+
+    // Matches the first path segment against the card types in the registry.
+    fn with_kind() -> impl Filter<Extract = (&'static dyn CardKind,), Error = warp::Rejection> + Clone {
+        warp::path::param()
+            .and_then(|typ: String| async move {
+                registry::get().find(&typ).ok_or_else(warp::reject::not_found)
+            })
     }
 
     fn with_db(db: Pool<SqliteConnectionManager>) -> impl Filter<Extract = (Pool<SqliteConnectionManager>,), Error = std::convert::Infallible> + Clone {
@@ -403,35 +793,550 @@ mod filters {
 mod handlers {
     use std::collections::HashMap;
     use std::convert::Infallible;
-    use r2d2::Pool;
+    use r2d2::{Pool, PooledConnection};
     use r2d2_sqlite::SqliteConnectionManager;
     use warp::http::{HeaderValue, StatusCode};
-    use warp::http::header::CONTENT_TYPE;
+    use warp::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG};
+    use warp::hyper::body::Bytes;
     use warp::Reply;
     use warp::reply::Response;
-    use crate::{Card, cards};
+    use crate::batch;
+    use crate::cards;
+    use crate::export;
+    use crate::ids;
+    use crate::import;
+    use crate::links;
+    use crate::quarantine;
+    use crate::registry::{self, CardKind};
+    use crate::search;
+
+    pub async fn types() -> Result<impl warp::Reply, Infallible> {
+
+        let types: Vec<serde_json::Value> = registry::get().kinds()
+            .map(|kind| serde_json::json!({
+                "type": kind.typ_str(),
+                "code": kind.code(),
+                "table": kind.sql_table(),
+                "builtin": kind.is_builtin(),
+            }))
+            .collect();
+
+        Ok(warp::reply::json(&types))
+    }
 
-    pub async fn count<T: Card>(db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+    pub async fn count(kind: &'static dyn CardKind, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
 
-        let db = db.get()
-            .expect("Cannot get DB connection from pool");
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
 
-        let count = db.query_row(format!("SELECT COUNT(*) FROM {}", T::sql_table()).as_str(), [],
-                     |row| row.get::<usize, usize>(0))
-            .expect("Cannot query project count");
+        let reply = match db.query_row(format!("SELECT COUNT(*) FROM {}", kind.sql_table()).as_str(), [],
+                                       |row| row.get::<usize, usize>(0)) {
+            Ok(count) => warp::reply::json(&count).into_response(),
+            Err(err) => find_error_reply(cards::Error::DatabaseError(err)),
+        };
 
-        Ok(warp::reply::json(&count))
+        Ok(reply)
     }
 
-    pub async fn list<T: Card>(query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+    pub async fn list(kind: &'static dyn CardKind, mut query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
 
-        let db = db.get()
-            .expect("Cannot get DB connection from pool");
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        // ids=... fetches the contents of the given cards rather than listing anything.
+        if let Some(ids) = query.remove("ids") {
+            let result = if query.is_empty() {
+                batch::parse_ids(&ids).and_then(|ids| batch::read_cards(kind, &ids, &db))
+            } else {
+                Err(cards::Error::InvalidQuery(String::from("ids cannot be combined with other parameters")))
+            };
+            return Ok(match result {
+                Ok(cards) => warp::reply::json(&cards).into_response(),
+                Err(e) => find_error_reply(e),
+            });
+        }
+
+        // With fields=... the index rows are returned instead of just the IDs.
+        let result = cards::ListOptions::take_from_query(&mut query)
+            .and_then(|options| match options.fields {
+                Some(_) => kind.sql_list_rows(&db, &query, &options),
+                None => kind.sql_list_ids(&db, &query, &options)
+                    .map(|ids| ids.iter().map(|id| serde_json::json!(id)).collect()),
+            });
+
+        let reply = match result {
+            Ok(ids) => warp::reply::json(&ids).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn cards(mut query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let result = cards::ListOptions::take_from_query(&mut query)
+            .and_then(|options| match options.fields {
+                Some(_) => cards::sql_list_all_card_rows(&db, &query, &options),
+                None => cards::sql_list_cards(&db, &query, &options)
+                    .map(|ids| ids.into_iter().map(serde_json::Value::String).collect()),
+            });
 
-        let ids = T::sql_list_ids(&db, &query)
-            .expect("Cannot list card IDs");
+        let reply = match result {
+            Ok(ids) => warp::reply::json(&ids).into_response(),
+            Err(e) => find_error_reply(e),
+        };
 
-        Ok(warp::reply::json(&ids))
+        Ok(reply)
+    }
+
+    pub async fn cards_batch(body: serde_json::Value, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let reply = match batch::parse_qualified_ids(&body).and_then(|ids| batch::read_qualified_cards(&ids, &db)) {
+            Ok(cards) => warp::reply::json(&cards).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn links(kind: &'static dyn CardKind, name_or_id: String, direction: links::Direction, query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let id = match kind.sql_find_id(&db, &name_or_id) {
+            Ok(id) => id,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let links = match links::sql_card_links(&db, kind.code(), id, direction, query.get("role").map(|s| s.as_str())) {
+            Ok(links) => links,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+        let links: Vec<serde_json::Value> = links.iter()
+            .map(|link| {
+                let other = if direction == links::Direction::Outgoing { link.to } else { link.from };
+                serde_json::json!({
+                    "role": link.role,
+                    "card": links::qualified_id(other.0, other.1),
+                    "dangling": link.dangling,
+                })
+            })
+            .collect();
+
+        Ok(warp::reply::json(&links).into_response())
+    }
+
+    pub async fn allocate_ids(query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let kind = match query.get("type").and_then(|typ| registry::get().find(typ)) {
+            Some(kind) => kind,
+            None => return Ok(find_error_reply(cards::Error::InvalidQuery(String::from("type must be a known card type")))),
+        };
+        let count = match query.get("count").map(|s| s.parse::<usize>()) {
+            None => 1,
+            Some(Ok(count)) if (1..=ids::MAX_IDS_PER_REQUEST).contains(&count) => count,
+            Some(_) => return Ok(find_error_reply(cards::Error::InvalidQuery(format!("count must be between 1 and {}", ids::MAX_IDS_PER_REQUEST)))),
+        };
+
+        let reply = match ids::allocate_ids(kind, count, &db) {
+            Ok(ids) => warp::reply::json(&ids).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn find_source(query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let source = match query.get("source") {
+            Some(source) => source,
+            None => return Ok(find_error_reply(cards::Error::InvalidQuery(String::from("source must be given")))),
+        };
+
+        let reply = match ids::sql_find_card_by_source(&db, source) {
+            Ok(Some((code, id))) => warp::reply::json(&links::qualified_id(code, id)).into_response(),
+            Ok(None) => find_error_reply(cards::Error::CantFindCard(source.clone())),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn import(body: Bytes, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        // Writing out a whole batch of cards takes a while, so keep it off the async workers.
+        let result = tokio::task::spawn_blocking(move || {
+            let input = std::str::from_utf8(&body)
+                .map_err(|_| cards::Error::InvalidQuery(String::from("Import must be UTF-8 encoded")))?;
+            let db = connect(&db)?;
+            import::import_cards(input, &db)
+        }).await;
+
+        let reply = match result {
+            Ok(Ok(results)) => warp::reply::json(&results.iter().map(|result| result.json()).collect::<Vec<serde_json::Value>>()).into_response(),
+            Ok(Err(e)) => find_error_reply(e),
+            Err(err) => problem_reply(StatusCode::INTERNAL_SERVER_ERROR, &format!("Import failed: {}", err)),
+        };
+
+        Ok(reply)
+    }
+
+    // Hands whatever is written to it to the task streaming the response.
+    struct ChannelWriter(tokio::sync::mpsc::Sender<Result<Bytes, ()>>);
+
+    impl std::io::Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client went away"))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub async fn export(query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<Box<dyn warp::Reply>, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(Box::new(find_error_reply(e))),
+        };
+
+        let mut filters = query;
+        let format = match export::Format::parse(filters.remove("format").as_deref().unwrap_or("ndjson")) {
+            Ok(format) => format,
+            Err(e) => return Ok(Box::new(find_error_reply(e))),
+        };
+        let export = match export::Export::prepare(&db, format, &filters) {
+            Ok(export) => export,
+            Err(e) => return Ok(Box::new(find_error_reply(e))),
+        };
+
+        // Exports can be big so they are streamed. The export is written on a blocking
+        // thread with a bounded channel to the response in between.
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Bytes, ()>>(16);
+        tokio::task::spawn_blocking(move || {
+            let mut out = std::io::BufWriter::new(ChannelWriter(tx.clone()));
+            let result = export.write(&db, &mut out);
+            drop(out);
+            if let Err(e) = result {
+                println!("Export failed: {}", e);
+                let _ = tx.blocking_send(Err(()));
+            }
+        });
+        let (mut sender, body) = warp::hyper::Body::channel();
+        tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                match chunk {
+                    Ok(chunk) => if sender.send_data(chunk).await.is_err() {
+                        break;
+                    },
+                    // Let the client know it didn't get everything.
+                    Err(()) => {
+                        sender.abort();
+                        break;
+                    },
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        response.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+        response.headers_mut()
+            .insert(CONTENT_DISPOSITION, HeaderValue::from_str(&format!("attachment; filename=\"{}\"", format.file_name())).unwrap());
+        Ok(Box::new(response))
+    }
+
+    pub async fn search(query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let q = match query.get("q") {
+            Some(q) => q,
+            None => return Ok(find_error_reply(cards::Error::InvalidQuery(String::from("q must be given")))),
+        };
+        let code = match query.get("type").map(|typ| registry::get().find(typ)) {
+            None => None,
+            Some(Some(kind)) => Some(kind.code()),
+            Some(None) => return Ok(find_error_reply(cards::Error::InvalidQuery(String::from("type must be a known card type")))),
+        };
+        let limit = match query.get("limit").map(|s| s.parse::<u32>()) {
+            None => 20,
+            Some(Ok(limit)) if (1..=search::MAX_SEARCH_RESULTS).contains(&limit) => limit,
+            Some(_) => return Ok(find_error_reply(cards::Error::InvalidQuery(format!("limit must be between 1 and {}", search::MAX_SEARCH_RESULTS)))),
+        };
+
+        let reply = match search::sql_search(&db, q, code, limit) {
+            Ok(hits) => warp::reply::json(&hits.iter()
+                    .map(|hit| serde_json::json!({
+                        "card": links::qualified_id(hit.card.0, hit.card.1),
+                        "title": hit.title,
+                        "snippet": hit.snippet,
+                        "score": hit.score,
+                    }))
+                    .collect::<Vec<serde_json::Value>>()).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn link_report(db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let reply = match links::sql_link_report(&db) {
+            Ok(report) => warp::reply::json(&report.json()).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn index_errors(db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let reply = match quarantine::sql_list_errors(&db) {
+            Ok(errors) => warp::reply::json(&errors.iter().map(|error| error.json()).collect::<Vec<serde_json::Value>>()).into_response(),
+            Err(e) => find_error_reply(e),
+        };
+
+        Ok(reply)
+    }
+
+    pub async fn graph(kind: &'static dyn CardKind, name_or_id: String, query: HashMap<String, String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let id = match kind.sql_find_id(&db, &name_or_id) {
+            Ok(id) => id,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+
+        let depth = match query.get("depth").map(|s| s.parse::<u32>()) {
+            None => 1,
+            Some(Ok(depth)) if (1..=links::MAX_GRAPH_DEPTH).contains(&depth) => depth,
+            Some(_) => return Ok(find_error_reply(cards::Error::InvalidQuery(format!("depth must be between 1 and {}", links::MAX_GRAPH_DEPTH)))),
+        };
+        let direction = match query.get("direction").map(|s| s.as_str()) {
+            None | Some("both") => links::Direction::Both,
+            Some("out") => links::Direction::Outgoing,
+            Some("in") => links::Direction::Incoming,
+            Some(_) => return Ok(find_error_reply(cards::Error::InvalidQuery(String::from("direction must be one of in, out or both")))),
+        };
+
+        let links::CardGraph { cards, links } = match links::sql_card_graph(&db, kind.code(), id, depth, direction, query.get("role").map(|s| s.as_str())) {
+            Ok(graph) => graph,
+            Err(e) => return Ok(find_error_reply(e)),
+        };
+        let graph = serde_json::json!({
+            "card": links::qualified_id(kind.code(), id),
+            "cards": cards.iter()
+                .map(|((code, id), depth)| serde_json::json!({ "card": links::qualified_id(*code, *id), "depth": depth }))
+                .collect::<Vec<serde_json::Value>>(),
+            "links": links.iter().map(|link| link.json()).collect::<Vec<serde_json::Value>>(),
+        });
+
+        Ok(warp::reply::json(&graph).into_response())
+    }
+
+    // Writes take WRITE_LOCK and do file I/O along with the SQLite work. Neither should hold
+    // up the async workers so all of it runs on a blocking thread.
+    async fn write_blocking<F>(db: Pool<SqliteConnectionManager>, write: F) -> Response
+        where F: FnOnce(&rusqlite::Connection) -> Result<Response, cards::Error> + Send + 'static {
+        let result = tokio::task::spawn_blocking(move || {
+            let db = connect(&db)?;
+            let _lock = crate::WRITE_LOCK.lock().unwrap();
+            write(&db)
+        }).await;
+        match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => find_error_reply(e),
+            Err(err) => problem_reply(StatusCode::INTERNAL_SERVER_ERROR, &format!("Write failed: {}", err)),
+        }
+    }
+
+    pub async fn create(kind: &'static dyn CardKind, card: serde_json::Value, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let mut card = match card {
+            serde_json::Value::Object(card) => card,
+            _ => return Ok(find_error_reply(cards::Error::InvalidCard(String::from("Card must be a JSON object")))),
+        };
+
+        Ok(write_blocking(db, move |db| {
+            let (id, replaces) = crate::prepare_new_card(kind, &mut card, db)?;
+            let etag = crate::write_card(kind, id, &serde_json::Value::Object(card), db)?;
+            Ok(warp::reply::with_header(warp::reply::with_status(warp::reply::json(&serde_json::json!({
+                    "id": id,
+                    "card": format!("{}/{}", kind.typ_str(), id),
+                })), if replaces { StatusCode::OK } else { StatusCode::CREATED }), ETAG, etag).into_response())
+        }).await)
+    }
+
+    pub async fn replace(kind: &'static dyn CardKind, id: u64, if_match: Option<String>, card: serde_json::Value, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        let mut card = match card {
+            serde_json::Value::Object(card) => card,
+            _ => return Ok(find_error_reply(cards::Error::InvalidCard(String::from("Card must be a JSON object")))),
+        };
+        if card.get("Id").is_some_and(|other| other.as_u64() != Some(id)) {
+            return Ok(find_error_reply(cards::Error::InvalidCard(String::from("Id does not match the card in the path"))));
+        }
+
+        Ok(write_blocking(db, move |db| {
+            let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?;
+            crate::check_if_match(&path, if_match.as_deref())?;
+            let existed = path.exists();
+
+            crate::fill_card_fields(kind, id, &mut card);
+            let etag = crate::write_card(kind, id, &serde_json::Value::Object(card), db)?;
+            Ok(warp::reply::with_header(warp::reply::with_status(warp::reply::json(&serde_json::json!({
+                    "id": id,
+                    "card": format!("{}/{}", kind.typ_str(), id),
+                })), if existed { StatusCode::OK } else { StatusCode::CREATED }), ETAG, etag).into_response())
+        }).await)
+    }
+
+    pub async fn patch(kind: &'static dyn CardKind, id: u64, if_match: Option<String>, patch: serde_json::Value, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+
+        if !patch.is_object() {
+            return Ok(find_error_reply(cards::Error::InvalidCard(String::from("Patch must be a JSON object"))));
+        }
+
+        Ok(write_blocking(db, move |db| {
+            let path = cards::sql_find_card_file(db, kind.typ_str(), kind.code(), id)?;
+            if !path.exists() {
+                return Err(cards::Error::CantFindCard(format!("{}/{}", kind.typ_str(), id)));
+            }
+            crate::check_if_match(&path, if_match.as_deref())?;
+            let mut card: serde_json::Value = serde_json::from_str(&cards::read_card_json(&path)?)
+                .map_err(|err| cards::Error::CantReadFormatOfCard(path.clone(), err))?;
+
+            cards::merge_patch(&mut card, &patch);
+            if card["Id"].as_u64() != Some(id) || card["Type"].as_str() != Some(kind.typ_str()) {
+                return Err(cards::Error::InvalidCard(String::from("Id and Type of a card cannot be patched")));
+            }
+            card["Modified"] = serde_json::json!(crate::timestamp_now());
+
+            let etag = crate::write_card(kind, id, &card, db)?;
+            Ok(warp::reply::with_header(warp::reply::json(&card), ETAG, etag).into_response())
+        }).await)
+    }
+
+    pub async fn delete(kind: &'static dyn CardKind, id: u64, if_match: Option<String>, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
+        Ok(write_blocking(db, move |db| {
+            crate::delete_card(kind, id, if_match.as_deref(), db)?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }).await)
+    }
+
+    // Errors go out as RFC 7807 problem details. We don't define problem types of our own so
+    // "type" is always about:blank and "title" the reason phrase of the status code.
+    fn problem_reply(status: StatusCode, detail: &str) -> Response {
+        let problem = serde_json::json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "detail": detail,
+        });
+        let mut response = warp::reply::with_status(warp::reply::json(&problem), status).into_response();
+        response.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+        response
+    }
+
+    fn find_error_reply(e: cards::Error) -> Response {
+        match e {
+            cards::Error::CantFindCard(e) => problem_reply(StatusCode::NOT_FOUND, &format!("Cannot find card: {}", e)),
+            cards::Error::InvalidCard(e) => problem_reply(StatusCode::BAD_REQUEST, &format!("Invalid card: {}", e)),
+            cards::Error::CardExists(e) => problem_reply(StatusCode::CONFLICT, &e),
+            cards::Error::InvalidQuery(e) => problem_reply(StatusCode::BAD_REQUEST, &e),
+            cards::Error::PreconditionFailed(e) => problem_reply(StatusCode::PRECONDITION_FAILED, &format!("Precondition failed: {}", e)),
+            // The indexer holds the database for a while when lots of cards change at once.
+            cards::Error::DatabaseUnavailable(e) => problem_reply(StatusCode::SERVICE_UNAVAILABLE, &e),
+            cards::Error::DatabaseError(e @ rusqlite::Error::SqliteFailure(rusqlite::ffi::Error {
+                code: rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked, ..
+            }, _)) => problem_reply(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+            e => {
+                println!("Request failed: {}", e);
+                problem_reply(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+            },
+        }
+    }
+
+    fn connect(db: &Pool<SqliteConnectionManager>) -> Result<PooledConnection<SqliteConnectionManager>, cards::Error> {
+        db.get()
+            .map_err(|err| cards::Error::DatabaseUnavailable(format!("Cannot get DB connection: {}", err)))
+    }
+
+    // Requests that didn't make it to a handler.
+    pub async fn rejection(rejection: warp::Rejection) -> Result<Response, Infallible> {
+        use warp::reject;
+        let (status, detail) = if rejection.is_not_found() {
+            (StatusCode::NOT_FOUND, String::from("No such endpoint or card type"))
+        } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+            (StatusCode::BAD_REQUEST, format!("Body is not valid JSON: {}", e))
+        } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+            (StatusCode::BAD_REQUEST, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+            (StatusCode::BAD_REQUEST, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::MissingHeader>() {
+            (StatusCode::BAD_REQUEST, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::PayloadTooLarge>() {
+            (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+            (StatusCode::LENGTH_REQUIRED, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+        } else if let Some(e) = rejection.find::<reject::MethodNotAllowed>() {
+            (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+        } else {
+            println!("Unhandled rejection: {:?}", rejection);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::from("Unhandled rejection"))
+        };
+        Ok(problem_reply(status, &detail))
     }
 
     struct Json {
@@ -453,72 +1358,140 @@ mod handlers {
         }
     }
 
-    pub async fn get<T: Card>(name_or_id: String, db: Pool<SqliteConnectionManager>) -> Result<impl warp::Reply, Infallible> {
-
-        let db = db.get()
-            .expect("Cannot get DB connection from pool");
+    pub async fn get(kind: &'static dyn CardKind, name_or_id: String, db: Pool<SqliteConnectionManager>) -> Result<Box<dyn warp::Reply>, Infallible> {
 
-        let (s, code) = match T::sql_find_id(&db, &name_or_id) {
-            Ok(id) => {
-                match T::json(id) {
-                    Ok(s) => (s, StatusCode::OK),
-                    Err(e) => (format!("Could not load {}: {:?}", name_or_id, e), StatusCode::INTERNAL_SERVER_ERROR),
-                }
-            },
-            Err(cards::Error::CantFindCard(e)) => (format!("Cannot find card: {}", e), StatusCode::NOT_FOUND),
-            Err(e) => (format!("Error: {:?}", e), StatusCode::INTERNAL_SERVER_ERROR),
+        let db = match connect(&db) {
+            Ok(db) => db,
+            Err(e) => return Ok(Box::new(find_error_reply(e))),
         };
 
-        // T::json gives us a string that is already serialized JSON data.
-        Ok(warp::reply::with_status(Json { inner: Ok(s.into_bytes()) }, code))
+        // Numeric IDs are taken as they are so the card may not actually exist.
+        let result = kind.sql_find_id(&db, &name_or_id)
+            .and_then(|id| match crate::card_exists(kind, id, &db)? {
+                true => kind.json(&db, id),
+                false => Err(cards::Error::CantFindCard(format!("{}/{}", kind.typ_str(), id))),
+            });
+        match result {
+            Ok(s) => {
+                // kind.json gives us the file contents so the ETag matches what PUT/PATCH/DELETE compare against.
+                let etag = cards::get_card_etag(s.as_bytes());
+                // It's also a string that is already serialized JSON data.
+                Ok(Box::new(warp::reply::with_header(Json { inner: Ok(s.into_bytes()) }, ETAG, etag)))
+            },
+            Err(e) => Ok(Box::new(find_error_reply(e))),
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
 
+    let config = match config::Config::load(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    config::init(config);
+    let config = config::get();
+
+    match schemas::load_schemas() {
+        Ok(schemas) => registry::init(registry::Registry::new(schemas)),
+        Err(err) => {
+            eprintln!("Cannot load card schemas: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // Looks at the card files only so doesn't need the index.
+    if config.command == config::Command::Validate {
+        std::process::exit(validate_cards());
+    }
+
     println!("Initializing database...");
-    let manager = SqliteConnectionManager::file("cards.sqlite");
+    let manager = SqliteConnectionManager::file(&config.db_path);
     let pool = r2d2::Pool::new(manager)
         .expect("Cannot create DB connection pool");
 
-    init_db(pool.clone().get().expect("Cannot get DB connection").deref())
-        .expect("Cannot initialize DB");
+    if let Err(err) = init_db(pool.clone().get().expect("Cannot get DB connection").deref()) {
+        eprintln!("Cannot initialize DB: {}", err);
+        std::process::exit(1);
+    }
 
-    let report_thread = report::spawn_thread();
+    if config.command == config::Command::CheckLinks {
+        std::process::exit(check_links(pool.get().expect("Cannot get DB connection").deref()));
+    }
+    if config.command == config::Command::Export {
+        std::process::exit(export_cards(pool.get().expect("Cannot get DB connection").deref(), &config.command_options));
+    }
+
+    let report_thread = report::spawn_thread(config.report.clone());
     report::update_report(&report_thread.channel);
 
-    let _project_watcher = init_watcher::<Project>(pool.clone(), report_thread.channel.clone());
-    let _task_watcher = init_watcher::<Task>(pool.clone(), report_thread.channel.clone());
-    let _status_watcher = init_watcher::<Status>(pool.clone(), report_thread.channel.clone());
-    let _timelog_watcher = init_watcher::<Timelog>(pool.clone(), report_thread.channel.clone());
-    let _purchase_watcher = init_watcher::<Purchase>(pool.clone(), report_thread.channel.clone());
-    let _book_watcher = init_watcher::<Book>(pool.clone(), report_thread.channel.clone());
-    let _metric_watcher = init_watcher::<Metric>(pool.clone(), report_thread.channel.clone());
-    let _word_watcher = init_watcher::<Word>(pool.clone(), report_thread.channel.clone());
-    let _note_watcher = init_watcher::<Note>(pool.clone(), report_thread.channel.clone());
-    let _thought_watcher = init_watcher::<Thought>(pool.clone(), report_thread.channel.clone());
-    let _achievement_watcher = init_watcher::<Achievement>(pool.clone(), report_thread.channel.clone());
-    let _notebook_watcher = init_watcher::<Notebook>(pool.clone(), report_thread.channel.clone());
+    let queue = watcher::spawn_indexer(pool.clone(), report_thread.channel.clone(), config.watch_debounce);
+    if let Some(interval) = config.rescan_interval {
+        watcher::spawn_reconciler(queue.clone(), interval);
+    }
+    let _watchers: Vec<watcher::FileWatcher> = registry::get().kinds()
+        .map(|kind| watcher::init_watcher(kind, queue.clone()))
+        .collect();
 
     println!("   Done.");
 
-    let api = filters::cards::<Project>(pool.clone())
-        .or(filters::cards::<Task>(pool.clone()))
-        .or(filters::cards::<Status>(pool.clone()))
-        .or(filters::cards::<Timelog>(pool.clone()))
-        .or(filters::cards::<Purchase>(pool.clone()))
-        .or(filters::cards::<Metric>(pool.clone()))
-        .or(filters::cards::<Word>(pool.clone()))
-        .or(filters::cards::<Note>(pool.clone()))
-        .or(filters::cards::<Thought>(pool.clone()))
-        .or(filters::cards::<Achievement>(pool.clone()))
-        .or(filters::cards::<Notebook>(pool.clone()))
-        .or(filters::cards::<Book>(pool.clone()));
+    let api = filters::api(pool.clone())
+        .recover(handlers::rejection);
 
     warp::serve(api)
-        .run(([127, 0, 0, 1], 8000))
+        .run(config.listen_addr)
         .await;
 
     report::quit_thread(report_thread);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_card_file(name: &str, contents: &str) -> (PathBuf, String) {
+        let path = std::env::temp_dir().join(format!("gulper-{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        (path, cards::get_card_etag(contents.as_bytes()))
+    }
+
+    fn is_precondition_failed(result: Result<(), cards::Error>) -> bool {
+        matches!(result, Err(cards::Error::PreconditionFailed(_)))
+    }
+
+    #[test]
+    fn if_match_passes_for_the_current_etag() {
+        let (path, etag) = write_card_file("if-match-current", r#"{"Id": 1}"#);
+        let results = [
+            check_if_match(&path, None),
+            check_if_match(&path, Some(&etag)),
+            check_if_match(&path, Some(&format!("\"0000000000000000\", {}", etag))),
+            check_if_match(&path, Some("*")),
+        ];
+        std::fs::remove_file(&path).unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[test]
+    fn if_match_fails_once_the_card_has_changed() {
+        let (path, etag) = write_card_file("if-match-changed", r#"{"Id": 1}"#);
+        std::fs::write(&path, r#"{"Id": 1, "Title": "Changed"}"#).unwrap();
+        let stale = check_if_match(&path, Some(&etag));
+        let weak = check_if_match(&path, Some(&format!("W/{}", cards::get_card_etag(&std::fs::read(&path).unwrap()))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_precondition_failed(stale));
+        assert!(is_precondition_failed(weak));
+    }
+
+    #[test]
+    fn if_match_fails_for_missing_cards() {
+        let path = std::env::temp_dir().join(format!("gulper-if-match-missing-{}.json", std::process::id()));
+        assert!(check_if_match(&path, None).is_ok());
+        assert!(is_precondition_failed(check_if_match(&path, Some("*"))));
+        assert!(is_precondition_failed(check_if_match(&path, Some("\"0000000000000000\""))));
+    }
+}
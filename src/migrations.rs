@@ -0,0 +1,328 @@
+use rusqlite::params;
+use crate::cards;
+
+// The schema of the index is versioned. The schema_version table records every migration
+// that has been applied to a database; the highest version in there is the version of the
+// database.
+//
+// MIGRATIONS is append-only. Version N is MIGRATIONS[N - 1]. Never change a migration once
+// it has shipped; add a new one that adds the column, index or card table instead. A new
+// card type gets its table through a migration that creates it with literal SQL.
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&rusqlite::Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { description: "initial schema", apply: initial_schema },
+    Migration { description: "index links and taggings by card", apply: index_links_and_taggings },
+    Migration { description: "track card types defined by schema files", apply: card_schemas },
+    Migration { description: "mark links to missing cards as dangling", apply: dangling_links },
+    Migration { description: "record where card files are", apply: card_file_paths },
+    Migration { description: "index cards by source", apply: index_sources },
+    Migration { description: "index card text for full-text search", apply: card_text },
+    Migration { description: "record cards that fail to load", apply: index_errors },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn current_version(db: &rusqlite::Connection) -> Result<u32, cards::Error> {
+    db.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description VARCHAR,
+            applied DATETIME NOT NULL
+        );"#)
+        .map_err(cards::Error::DatabaseError)?;
+
+    let version = db.query_row("SELECT MAX(version) FROM schema_version", [],
+                               |row| row.get::<usize, Option<u32>>(0))
+        .map_err(cards::Error::DatabaseError)?;
+
+    Ok(version.unwrap_or(0))
+}
+
+// Bring the database up to latest_version(). Refuses to touch databases written by a newer
+// binary as we can't know what it has changed.
+pub fn migrate(db: &rusqlite::Connection) -> Result<(), cards::Error> {
+
+    let version = current_version(db)?;
+    if version > latest_version() {
+        return Err(cards::Error::DatabaseUnavailable(format!(
+            "database has schema version {} but this build of gulper_index only understands up to version {}",
+            version, latest_version())));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = index as u32 + 1;
+        println!("   Migrating database to version {} ({})", version, migration.description);
+
+        db.execute("BEGIN TRANSACTION", [])
+            .map_err(cards::Error::DatabaseError)?;
+
+        let result = (migration.apply)(db)
+            .and_then(|_| db.execute("INSERT INTO schema_version (version, description, applied) VALUES(?1, ?2, datetime('now'))",
+                                     params![version, migration.description]));
+
+        if let Err(err) = result {
+            db.execute("ROLLBACK", [])
+                .map_err(cards::Error::DatabaseError)?;
+            return Err(cards::Error::DatabaseError(err));
+        }
+
+        db.execute("COMMIT", [])
+            .map_err(cards::Error::DatabaseError)?;
+    }
+
+    Ok(())
+}
+
+fn initial_schema(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+
+    // Databases without a schema version were rebuilt from scratch on every start so there
+    // is nothing in them worth keeping. Start over to not pick up duplicate links and tags.
+    db.execute_batch(r#"
+        DROP TABLE IF EXISTS Tags;
+        DROP TABLE IF EXISTS Taggings;
+        DROP TABLE IF EXISTS Links;
+        DROP TABLE IF EXISTS CardFiles;
+        DROP TABLE IF EXISTS Projects;
+        DROP TABLE IF EXISTS Tasks;
+        DROP TABLE IF EXISTS Statuses;
+        DROP TABLE IF EXISTS Timelogs;
+        DROP TABLE IF EXISTS Purchases;
+        DROP TABLE IF EXISTS Metrics;
+        DROP TABLE IF EXISTS Words;
+        DROP TABLE IF EXISTS Notes;
+        DROP TABLE IF EXISTS Thoughts;
+        DROP TABLE IF EXISTS Achievements;
+        DROP TABLE IF EXISTS Notebooks;
+        DROP TABLE IF EXISTS Books;
+        CREATE TABLE Tags (
+            name VARCHAR PRIMARY KEY UNIQUE
+        );
+        CREATE TABLE Taggings (
+            tag_id INTEGER,
+            card_type INTEGER,
+            card_id INTEGER
+        );
+        CREATE TABLE Links (
+            role VARCHAR,
+            from_type INTEGER,
+            from_id INTEGER,
+            to_type INTEGER,
+            to_id INTEGER
+        );
+        CREATE TABLE CardFiles (
+            card_type INTEGER,
+            card_id INTEGER,
+            mtime INTEGER,
+            size INTEGER,
+            hash INTEGER,
+            PRIMARY KEY (card_type, card_id)
+        );"#)?;
+
+    // The built-in card tables. They aren't defined anywhere else; changes to them go into
+    // new migrations.
+    db.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS Projects (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            started DATETIME,
+            finished DATETIME,
+            active BOOLEAN DEFAULT 1
+        );
+        CREATE INDEX IF NOT EXISTS ProjectsByName ON Projects(title);
+        CREATE TABLE IF NOT EXISTS Tasks (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            completed DATETIME,
+            obsolete BOOLEAN
+        );
+        CREATE INDEX IF NOT EXISTS TasksByDescription ON Tasks(title);
+        CREATE TABLE IF NOT EXISTS Statuses (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            began DATETIME,
+            ended DATETIME
+        );
+        CREATE TABLE IF NOT EXISTS Timelogs (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            started DATETIME NOT NULL,
+            ended DATETIME,
+            category VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Purchases (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            date DATETIME,
+            price REAL,
+            currency CHAR(3),
+            used BOOLEAN,
+            store VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Metrics (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            timestamp DATETIME,
+            amount REAL
+        );
+        CREATE TABLE IF NOT EXISTS Words (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            language CHAR(3),
+            category VARCHAR,
+            gender CHAR(1)
+        );
+        CREATE TABLE IF NOT EXISTS Notes (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            text VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Thoughts (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Achievements (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            date VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Notebooks (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            description VARCHAR,
+            location VARCHAR,
+            format VARCHAR,
+            pages INTEGER,
+            started VARCHAR,
+            ended VARCHAR
+        );
+        CREATE TABLE IF NOT EXISTS Books (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            authors VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR,
+            year INTEGER,
+            started DATETIME,
+            completed DATETIME,
+            cover VARCHAR,
+            ident VARCHAR
+        );"#)
+}
+
+fn index_links_and_taggings(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(r#"
+        CREATE INDEX LinksByFrom ON Links(from_type, from_id);
+        CREATE INDEX LinksByTo ON Links(to_type, to_id);
+        CREATE INDEX TaggingsByCard ON Taggings(card_type, card_id);
+        CREATE INDEX TaggingsByTag ON Taggings(tag_id);"#)
+}
+
+fn card_schemas(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(r#"
+        CREATE TABLE CardSchemas (
+            type VARCHAR PRIMARY KEY,
+            code INTEGER NOT NULL,
+            tbl VARCHAR NOT NULL,
+            hash INTEGER NOT NULL
+        );"#)
+}
+
+fn dangling_links(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // Incoming links used to be deleted along with their target so existing links may still
+    // point at cards that were never indexed.
+    db.execute_batch(r#"
+        ALTER TABLE Links ADD COLUMN dangling BOOLEAN NOT NULL DEFAULT 0;
+        UPDATE Links SET dangling = NOT EXISTS (SELECT 1 FROM CardFiles WHERE card_type IS Links.to_type AND card_id IS Links.to_id);"#)
+}
+
+fn card_file_paths(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // Relative to the type folder. Cards indexed before this have to be at the top of it.
+    db.execute_batch(r#"
+        ALTER TABLE CardFiles ADD COLUMN path VARCHAR;
+        UPDATE CardFiles SET path = card_id || '.json';"#)
+}
+
+fn index_sources(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // Tables of schema types created from now on come with the index (see CardSchema::sql_schema()).
+    let mut tables: Vec<String> = [
+        "Projects", "Tasks", "Statuses", "Timelogs", "Purchases", "Metrics", "Words", "Notes",
+        "Thoughts", "Achievements", "Notebooks", "Books",
+    ].iter().map(|table| String::from(*table)).collect();
+    let mut stmt = db.prepare("SELECT tbl FROM CardSchemas")?;
+    for table in stmt.query_map([], |row| row.get::<usize, String>(0))? {
+        tables.push(table?);
+    }
+    for table in tables.iter() {
+        db.execute_batch(&format!("CREATE INDEX IF NOT EXISTS {}BySource ON {}(source);", table, table))?;
+    }
+    Ok(())
+}
+
+fn card_text(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // See search.rs. Forgetting the file stamps makes the next sync re-index every card, which
+    // fills in the text of cards that were indexed before.
+    db.execute_batch(r#"
+        CREATE TABLE CardTextKeys (
+            rowid INTEGER PRIMARY KEY,
+            card_type INTEGER NOT NULL,
+            card_id INTEGER NOT NULL,
+            UNIQUE (card_type, card_id)
+        );
+        CREATE VIRTUAL TABLE CardText USING fts5(title, body, tags, tokenize = 'unicode61 remove_diacritics 2');
+        UPDATE CardFiles SET mtime = 0, size = 0, hash = 0;"#)
+}
+
+fn index_errors(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // See quarantine.rs. Cards that failed to load before this were simply skipped and have
+    // no CardFiles entry, so the next sync finds them again.
+    db.execute_batch(r#"
+        CREATE TABLE IndexErrors (
+            card_type INTEGER NOT NULL,
+            card_id INTEGER NOT NULL,
+            path VARCHAR NOT NULL,
+            error VARCHAR NOT NULL,
+            time VARCHAR NOT NULL,
+            PRIMARY KEY (card_type, card_id)
+        );"#)
+}
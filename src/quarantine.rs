@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+use rusqlite::params;
+use crate::cards::{self, Error};
+use crate::links;
+use crate::registry::{self, CardKind};
+
+// Cards that fail to load are kept out of the index but not forgotten. What went wrong goes
+// into IndexErrors so that one broken file (usually from a scraper gone wrong) neither stops
+// the rest from being indexed nor goes unnoticed; GET /_errors lists them. As quarantined
+// cards have no CardFiles entry, every sync tries them again. The entry goes away once the
+// card loads or its file is deleted.
+//
+// The validate command checks the card files directly and doesn't go through the index.
+
+pub struct IndexError {
+    pub card: (u32, u64),
+    pub path: PathBuf,
+    pub error: String,
+    pub time: String,
+}
+
+impl IndexError {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "card": links::qualified_id(self.card.0, self.card.1),
+            "path": self.path.display().to_string(),
+            "error": self.error,
+            "time": self.time,
+        })
+    }
+}
+
+// Whether the error is down to the card itself rather than the index.
+pub fn is_card_error(e: &Error) -> bool {
+    matches!(e, Error::CantAccessCard(..) | Error::CantReadFormatOfCard(..) | Error::CantReadProperty(_) | Error::InvalidCard(_))
+}
+
+pub fn sql_record_error(db: &rusqlite::Connection, kind: &dyn CardKind, id: u64, path: &Path, error: &Error) -> Result<(), Error> {
+    db.execute("INSERT OR REPLACE INTO IndexErrors (card_type, card_id, path, error, time) VALUES(?1, ?2, ?3, ?4, ?5)",
+               params![kind.code(), id, cards::get_relative_card_path(kind.typ_str(), path), error.reason(), crate::timestamp_now()])
+        .map_err(Error::DatabaseError)?;
+    Ok(())
+}
+
+pub fn sql_clear_error(db: &rusqlite::Connection, code: u32, id: u64) -> Result<(), Error> {
+    db.execute("DELETE FROM IndexErrors WHERE card_type IS ?1 AND card_id IS ?2", params![code, id])
+        .map_err(Error::DatabaseError)?;
+    Ok(())
+}
+
+// Forget all errors for cards of the given type, e.g. before they are all tried again.
+pub fn sql_clear_errors(db: &rusqlite::Connection, code: u32) -> Result<(), Error> {
+    db.execute("DELETE FROM IndexErrors WHERE card_type IS ?1", params![code])
+        .map_err(Error::DatabaseError)?;
+    Ok(())
+}
+
+pub fn sql_list_errors(db: &rusqlite::Connection) -> Result<Vec<IndexError>, Error> {
+    let mut stmt = db.prepare("SELECT card_type, card_id, path, error, time FROM IndexErrors ORDER BY card_type, card_id")
+        .map_err(Error::DatabaseError)?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<usize, u32>(0)?, row.get::<usize, u64>(1)?,
+                                            row.get::<usize, String>(2)?, row.get::<usize, String>(3)?, row.get::<usize, String>(4)?)))
+        .map_err(Error::DatabaseError)?;
+
+    let mut errors = Vec::new();
+    for row in rows {
+        let (code, id, path, error, time) = row.map_err(Error::DatabaseError)?;
+        let path = match registry::get().find_by_code(code) {
+            Some(kind) => cards::get_path_to_card_type(kind.typ_str()).join(path),
+            None => PathBuf::from(path),
+        };
+        errors.push(IndexError { card: (code, id), path, error, time });
+    }
+    Ok(errors)
+}
+
+// Load the given card into the index or, if there's something wrong with it, record why
+// not. Returns whether the card was loaded. Only problems with the index itself are errors.
+pub fn load_or_quarantine(kind: &dyn CardKind, id: u64, path: &Path, db: &rusqlite::Connection) -> Result<bool, Error> {
+
+    // Don't leave half a card behind.
+    db.execute("SAVEPOINT load_card", [])
+        .map_err(Error::DatabaseError)?;
+    let result = crate::load_card_into_db(kind, id, path, db);
+    if result.is_err() {
+        db.execute("ROLLBACK TO load_card", [])
+            .map_err(Error::DatabaseError)?;
+    }
+    db.execute("RELEASE load_card", [])
+        .map_err(Error::DatabaseError)?;
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if is_card_error(&e) => {
+            println!("Skipping card {}/{}: {}", kind.typ_str(), id, e);
+            sql_record_error(db, kind, id, path, &e)?;
+            Ok(false)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// Take the card out of the index and record why, e.g. when its file can't be read.
+pub fn quarantine_card(kind: &dyn CardKind, id: u64, path: &Path, error: &Error, db: &rusqlite::Connection) -> Result<(), Error> {
+    println!("Skipping card {}/{}: {}", kind.typ_str(), id, error);
+    crate::remove_card_from_db(kind.sql_table(), kind.code(), id, db)?;
+    sql_record_error(db, kind, id, path, error)
+}
+
+// A card file that doesn't hold a valid card.
+pub struct InvalidCardFile {
+    pub kind: &'static dyn CardKind,
+    pub id: u64,
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+// Every card file of every type that doesn't hold a valid card, along with what's wrong with
+// it. Also returns how many card files were checked.
+pub fn validate_card_files() -> Result<(usize, Vec<InvalidCardFile>), Error> {
+    let mut count = 0;
+    let mut invalid = Vec::new();
+    for kind in registry::get().kinds() {
+        for (id, path) in cards::list_card_files(kind.typ_str())? {
+            count += 1;
+            if let Err(error) = kind.validate(id, &path) {
+                invalid.push(InvalidCardFile { kind, id, path, error });
+            }
+        }
+    }
+    Ok((count, invalid))
+}
@@ -0,0 +1,562 @@
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use rusqlite::types::Value;
+use crate::cards::{self, Error};
+
+// Query language used by the _where parameter. Queries are parsed into an Expr and then
+// compiled into a parameterised SQL condition against a card table, so clients never get
+// to see (or inject) SQL.
+//
+//   query      := or
+//   or         := and ("OR" and)*
+//   and        := not (["AND"] not)*
+//   not        := "NOT" not | "(" query ")" | term
+//   term       := "tag:" value
+//               | "linked-to:" [role ":"] type "/" id
+//               | column op value
+//               | column "contains" value
+//               | column "in" value ".." value
+//   op         := "=" | "!=" | "<" | "<=" | ">" | ">="
+//   value      := number | 'text' | "text" | true | false | null | date | bareword
+//   date       := YYYY-MM-DD | "today" [("+" | "-") N ("d" | "w" | "m" | "y")]
+//
+// Keywords are case-insensitive. Juxtaposed terms are ANDed. Ranges include the lower
+// bound and exclude the upper one. Comparisons against dates only look at the date part
+// of the column so "started = today" matches any time today.
+//
+// Examples:
+//
+//   category = Reading AND started >= today-7d
+//   started in 2022-01-01..2022-02-01 NOT tag:work
+//   title contains 'daily report' OR linked-to:project/12
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Date(NaiveDate),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(String, Op, Literal),
+    Contains(String, String),
+    Range(String, Literal, Literal),
+    Tag(String),
+    LinkedTo(Option<String>, String, u64),
+}
+
+// What a query gets compiled against. `type_code` is the SQL expression that yields the
+// type code of the card in the current row; for a single card table that's just the code
+// of its type.
+pub struct SqlContext<'a> {
+    pub columns: &'a [String],
+    pub type_code: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Op(Op),
+    Range,
+    Word(String),
+    Quoted(String),
+}
+
+fn error(pos: usize, msg: &str) -> Error {
+    Error::InvalidQuery(format!("{} (at position {})", msg, pos))
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, Error> {
+
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        match c {
+            c if c.is_whitespace() => { i += 1; },
+            '(' => { tokens.push((start, Token::LParen)); i += 1; },
+            ')' => { tokens.push((start, Token::RParen)); i += 1; },
+            '=' => { tokens.push((start, Token::Op(Op::Eq))); i += 1; },
+            '!' if chars.get(i + 1) == Some(&'=') => { tokens.push((start, Token::Op(Op::Ne))); i += 2; },
+            '<' if chars.get(i + 1) == Some(&'=') => { tokens.push((start, Token::Op(Op::Le))); i += 2; },
+            '<' => { tokens.push((start, Token::Op(Op::Lt))); i += 1; },
+            '>' if chars.get(i + 1) == Some(&'=') => { tokens.push((start, Token::Op(Op::Ge))); i += 2; },
+            '>' => { tokens.push((start, Token::Op(Op::Gt))); i += 1; },
+            '\'' | '"' => {
+                // Quotes are escaped by doubling them, like in SQL.
+                let quote = c;
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(error(start, "unterminated string")),
+                        Some(&ch) if ch == quote => {
+                            if chars.get(i + 1) == Some(&quote) {
+                                text.push(quote);
+                                i += 2;
+                            } else {
+                                i += 1;
+                                break;
+                            }
+                        },
+                        Some(&ch) => { text.push(ch); i += 1; },
+                    }
+                }
+                tokens.push((start, Token::Quoted(text)));
+            },
+            _ => {
+                let mut word = String::new();
+                while i < chars.len() {
+                    let ch = chars[i];
+                    if ch.is_whitespace() || "()=!<>'\"".contains(ch) {
+                        break;
+                    }
+                    if ch == '.' && chars.get(i + 1) == Some(&'.') {
+                        break;
+                    }
+                    word.push(ch);
+                    i += 1;
+                }
+                if word.is_empty() {
+                    // Must be "..".
+                    if chars.get(i) == Some(&'.') && chars.get(i + 1) == Some(&'.') {
+                        tokens.push((start, Token::Range));
+                        i += 2;
+                        continue;
+                    }
+                    return Err(error(start, &format!("unexpected '{}'", c)));
+                }
+                tokens.push((start, Token::Word(word)));
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map(|(p, _)| *p).unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.parse_and()?;
+        while self.is_keyword("or") {
+            self.next();
+            let rhs = self.parse_and()?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.parse_not()?;
+        loop {
+            if self.is_keyword("and") {
+                self.next();
+            } else if self.peek().is_none() || self.peek() == Some(&Token::RParen) || self.is_keyword("or") {
+                break;
+            }
+            let rhs = self.parse_not()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, Error> {
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.next();
+            let expr = self.parse_or()?;
+            if self.next() != Some(Token::RParen) {
+                return Err(error(self.offset(), "expected ')'"));
+            }
+            return Ok(expr);
+        }
+        self.parse_term()
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, Error> {
+
+        let start = self.offset();
+        let word = match self.next() {
+            Some(Token::Word(w)) => w,
+            Some(_) => return Err(error(start, "expected a column name, tag: or linked-to:")),
+            None => return Err(error(start, "unexpected end of query")),
+        };
+
+        let lower = word.to_lowercase();
+        if let Some(tag) = lower.strip_prefix("tag:").map(|_| &word[4..]) {
+            let tag = if tag.is_empty() { self.parse_text(start)? } else { String::from(tag) };
+            return Ok(Expr::Tag(tag));
+        }
+        if let Some(target) = lower.strip_prefix("linked-to:").map(|_| &word[10..]) {
+            let (role, qualified_id) = match (target.find(':'), target.find('/')) {
+                (Some(colon), Some(slash)) if colon < slash => (Some(String::from(&target[..colon])), &target[(colon + 1)..]),
+                _ => (None, target),
+            };
+            let slash = qualified_id.find('/')
+                .ok_or_else(|| error(start, "linked-to: expects <type>/<id>"))?;
+            let id = qualified_id[(slash + 1)..].parse::<u64>()
+                .map_err(|_| error(start, "linked-to: expects a numeric card ID"))?;
+            return Ok(Expr::LinkedTo(role, String::from(&qualified_id[..slash]), id));
+        }
+
+        let column = word;
+        if self.is_keyword("contains") {
+            self.next();
+            return Ok(Expr::Contains(column, self.parse_text(start)?));
+        }
+        if self.is_keyword("in") {
+            self.next();
+            let lo = self.parse_literal()?;
+            if self.next() != Some(Token::Range) {
+                return Err(error(self.offset(), "expected '..' in range"));
+            }
+            let hi = self.parse_literal()?;
+            return Ok(Expr::Range(column, lo, hi));
+        }
+        match self.next() {
+            Some(Token::Op(op)) => Ok(Expr::Compare(column, op, self.parse_literal()?)),
+            _ => Err(error(start, &format!("expected an operator, 'contains' or 'in' after '{}'", column))),
+        }
+    }
+
+    fn parse_text(&mut self, start: usize) -> Result<String, Error> {
+        match self.next() {
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) => Ok(w),
+            _ => Err(error(start, "expected a value")),
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal, Error> {
+        let start = self.offset();
+        match self.next() {
+            Some(Token::Quoted(s)) => Ok(Literal::Text(s)),
+            Some(Token::Word(w)) => parse_bare_literal(&w).map_err(|msg| error(start, &msg)),
+            _ => Err(error(start, "expected a value")),
+        }
+    }
+}
+
+fn parse_bare_literal(word: &str) -> Result<Literal, String> {
+    let lower = word.to_lowercase();
+    if lower == "null" {
+        return Ok(Literal::Null);
+    }
+    if lower == "true" {
+        return Ok(Literal::Integer(1));
+    }
+    if lower == "false" {
+        return Ok(Literal::Integer(0));
+    }
+    if let Ok(i) = word.parse::<i64>() {
+        return Ok(Literal::Integer(i));
+    }
+    if let Ok(f) = word.parse::<f64>() {
+        return Ok(Literal::Real(f));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+        return Ok(Literal::Date(date));
+    }
+    if let Some(rest) = lower.strip_prefix("today") {
+        return parse_relative_date(rest).map(Literal::Date);
+    }
+    Ok(Literal::Text(String::from(word)))
+}
+
+// "" or "+3d", "-2w", "-1m", "+1y" relative to today.
+fn parse_relative_date(offset: &str) -> Result<NaiveDate, String> {
+
+    let today = Local::now().naive_local().date();
+    if offset.is_empty() {
+        return Ok(today);
+    }
+
+    let invalid = || format!("'today{}' is not a valid date (expected e.g. today-7d)", offset);
+    let (sign, rest) = if let Some(rest) = offset.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = offset.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return Err(invalid());
+    };
+    let unit = rest.chars().last().ok_or_else(invalid)?;
+    let amount = &rest[..(rest.len() - unit.len_utf8())];
+    if amount.is_empty() || !amount.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let amount = amount.parse::<i32>().map_err(|_| invalid())? * sign;
+
+    fn add_months(date: NaiveDate, months: i32) -> Option<NaiveDate> {
+        let total = date.year() * 12 + date.month0() as i32 + months;
+        let (year, month0) = (total.div_euclid(12), total.rem_euclid(12) as u32);
+        // Clamp the day to the end of the target month.
+        (1..=date.day()).rev()
+            .find_map(|day| NaiveDate::from_ymd_opt(year, month0 + 1, day))
+    }
+
+    match unit {
+        'd' => today.checked_add_signed(Duration::days(amount as i64)),
+        'w' => today.checked_add_signed(Duration::weeks(amount as i64)),
+        'm' => add_months(today, amount),
+        'y' => add_months(today, amount * 12),
+        _ => None,
+    }
+        .ok_or_else(invalid)
+}
+
+pub fn parse(query: &str) -> Result<Expr, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+        end: query.chars().count(),
+    };
+    if parser.peek().is_none() {
+        return Err(error(0, "empty query"));
+    }
+    let expr = parser.parse_or()?;
+    if parser.peek().is_some() {
+        return Err(error(parser.offset(), "unexpected input"));
+    }
+    Ok(expr)
+}
+
+impl Expr {
+
+    // Compile into an SQL condition. Values go into `params` and are referenced by number
+    // so the condition can be combined with other numbered parameters.
+    pub fn to_sql(&self, context: &SqlContext, params: &mut Vec<Value>) -> Result<String, Error> {
+
+        fn bind(params: &mut Vec<Value>, value: Value) -> String {
+            params.push(value);
+            format!("?{}", params.len())
+        }
+
+        fn column<'a>(context: &'a SqlContext, name: &str) -> Result<&'a str, Error> {
+            context.columns.iter()
+                .find(|c| c.eq_ignore_ascii_case(name))
+                .map(|c| c.as_str())
+                .ok_or_else(|| Error::InvalidQuery(format!("unknown property '{}'", name)))
+        }
+
+        // Dates only compare the date part of the column.
+        fn operand(column: &str, literal: &Literal) -> String {
+            match literal {
+                Literal::Date(_) => format!("substr({}, 1, 10)", column),
+                _ => String::from(column),
+            }
+        }
+
+        fn value(literal: &Literal) -> Value {
+            match literal {
+                Literal::Null => Value::Null,
+                Literal::Integer(i) => Value::Integer(*i),
+                Literal::Real(f) => Value::Real(*f),
+                Literal::Text(s) => Value::Text(s.clone()),
+                Literal::Date(d) => Value::Text(d.format("%Y-%m-%d").to_string()),
+            }
+        }
+
+        Ok(match self {
+            Expr::And(lhs, rhs) => format!("({} AND {})", lhs.to_sql(context, params)?, rhs.to_sql(context, params)?),
+            Expr::Or(lhs, rhs) => format!("({} OR {})", lhs.to_sql(context, params)?, rhs.to_sql(context, params)?),
+            Expr::Not(expr) => format!("(NOT {})", expr.to_sql(context, params)?),
+            Expr::Compare(name, op, literal) => {
+                let op = match op {
+                    Op::Eq => "IS",
+                    Op::Ne => "IS NOT",
+                    Op::Lt => "<",
+                    Op::Le => "<=",
+                    Op::Gt => ">",
+                    Op::Ge => ">=",
+                };
+                let lhs = operand(column(context, name)?, literal);
+                format!("{} {} {}", lhs, op, bind(params, value(literal)))
+            },
+            Expr::Contains(name, text) => {
+                let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+                format!("{} LIKE {} ESCAPE '\\'", column(context, name)?, bind(params, Value::Text(pattern)))
+            },
+            Expr::Range(name, lo, hi) => {
+                let column = column(context, name)?;
+                format!("({} >= {} AND {} < {})",
+                        operand(column, lo), bind(params, value(lo)),
+                        operand(column, hi), bind(params, value(hi)))
+            },
+            Expr::Tag(tag) => {
+                format!("id IN (SELECT card_id FROM Taggings JOIN Tags ON Tags.rowid = Taggings.tag_id WHERE Tags.name LIKE {} AND Taggings.card_type IS {})",
+                        bind(params, Value::Text(tag.clone())), context.type_code)
+            },
+            Expr::LinkedTo(role, typ, id) => {
+                let to_type = cards::get_type_code(typ);
+                let role_condition = match role {
+                    Some(role) => format!(" AND role IS {}", bind(params, Value::Text(role.clone()))),
+                    None => String::new(),
+                };
+                format!("id IN (SELECT from_id FROM Links WHERE from_type IS {} AND to_type IS {} AND to_id IS {}{})",
+                        context.type_code, bind(params, Value::Integer(to_type as i64)), bind(params, Value::Integer(*id as i64)), role_condition)
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<String> {
+        ["id", "title", "category", "started"].iter().map(|c| String::from(*c)).collect()
+    }
+
+    fn compile(query: &str) -> Result<(String, Vec<Value>), Error> {
+        let columns = columns();
+        let context = SqlContext { columns: &columns, type_code: String::from("4") };
+        let mut params = Vec::new();
+        let sql = parse(query)?.to_sql(&context, &mut params)?;
+        Ok((sql, params))
+    }
+
+    fn is_invalid(result: Result<Expr, Error>) -> bool {
+        matches!(result, Err(Error::InvalidQuery(_)))
+    }
+
+    #[test]
+    fn tokenizes_operators_quotes_and_ranges() {
+        let tokens: Vec<Token> = tokenize("a<=1 b != 'it''s' c in 1..2").unwrap()
+            .into_iter().map(|(_, t)| t).collect();
+        assert_eq!(tokens, vec![
+            Token::Word(String::from("a")), Token::Op(Op::Le), Token::Word(String::from("1")),
+            Token::Word(String::from("b")), Token::Op(Op::Ne), Token::Quoted(String::from("it's")),
+            Token::Word(String::from("c")), Token::Word(String::from("in")),
+            Token::Word(String::from("1")), Token::Range, Token::Word(String::from("2")),
+        ]);
+    }
+
+    #[test]
+    fn tokenizer_rejects_malformed_input() {
+        assert!(tokenize("title = 'open").is_err());
+        assert!(tokenize("title ! 1").is_err());
+    }
+
+    #[test]
+    fn parses_precedence_and_juxtaposition() {
+        let expr = parse("a = 1 b = 2 OR NOT c = 3").unwrap();
+        assert_eq!(expr, Expr::Or(
+            Box::new(Expr::And(
+                Box::new(Expr::Compare(String::from("a"), Op::Eq, Literal::Integer(1))),
+                Box::new(Expr::Compare(String::from("b"), Op::Eq, Literal::Integer(2))))),
+            Box::new(Expr::Not(Box::new(Expr::Compare(String::from("c"), Op::Eq, Literal::Integer(3)))))));
+    }
+
+    #[test]
+    fn parses_tags_and_links() {
+        assert_eq!(parse("tag:work").unwrap(), Expr::Tag(String::from("work")));
+        assert_eq!(parse("linked-to:about:project/12").unwrap(),
+                   Expr::LinkedTo(Some(String::from("about")), String::from("project"), 12));
+        assert!(is_invalid(parse("linked-to:project/x")));
+    }
+
+    #[test]
+    fn parser_rejects_malformed_input() {
+        for query in ["", "(", "(a = 1", "a = 1)", "a", "a =", "a in 1", "a in 1..", "= 1", "a = 1 OR"] {
+            assert!(is_invalid(parse(query)), "'{}' should not parse", query);
+        }
+    }
+
+    #[test]
+    fn parses_relative_dates() {
+        let today = Local::now().naive_local().date();
+        assert_eq!(parse_bare_literal("today"), Ok(Literal::Date(today)));
+        assert_eq!(parse_bare_literal("today-7d"), Ok(Literal::Date(today - Duration::days(7))));
+        assert_eq!(parse_bare_literal("TODAY+2w"), Ok(Literal::Date(today + Duration::weeks(2))));
+        assert!(matches!(parse_bare_literal("today-1m"), Ok(Literal::Date(_))));
+        assert!(matches!(parse_bare_literal("today+1y"), Ok(Literal::Date(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_relative_dates() {
+        for word in ["today+", "today-", "today+d", "today+é", "today-7é", "today7d", "today+-7d", "today++7d", "today-7x", "today-99999999999d"] {
+            assert!(parse_bare_literal(word).is_err(), "'{}' should not parse", word);
+        }
+        assert!(is_invalid(parse("started >= today-")));
+    }
+
+    #[test]
+    fn compiles_comparisons_with_parameters() {
+        let (sql, params) = compile("category = Reading AND title != null").unwrap();
+        assert_eq!(sql, "(category IS ?1 AND title IS NOT ?2)");
+        assert_eq!(params, vec![Value::Text(String::from("Reading")), Value::Null]);
+    }
+
+    #[test]
+    fn compiles_dates_against_the_date_part() {
+        let (sql, params) = compile("started in 2022-01-01..2022-02-01").unwrap();
+        assert_eq!(sql, "(substr(started, 1, 10) >= ?1 AND substr(started, 1, 10) < ?2)");
+        assert_eq!(params, vec![Value::Text(String::from("2022-01-01")), Value::Text(String::from("2022-02-01"))]);
+    }
+
+    #[test]
+    fn compiles_contains_with_escaped_pattern() {
+        let (sql, params) = compile("title contains '50%_off'").unwrap();
+        assert_eq!(sql, "title LIKE ?1 ESCAPE '\\'");
+        assert_eq!(params, vec![Value::Text(String::from("%50\\%\\_off%"))]);
+    }
+
+    #[test]
+    fn compiles_tags_and_links_against_the_type_code() {
+        let (sql, _) = compile("tag:work").unwrap();
+        assert!(sql.contains("Taggings.card_type IS 4"));
+        let (sql, params) = compile("linked-to:project/12").unwrap();
+        assert!(sql.contains("from_type IS 4"));
+        assert_eq!(params[1], Value::Integer(12));
+    }
+
+    #[test]
+    fn rejects_unknown_columns() {
+        assert!(matches!(compile("nope = 1"), Err(Error::InvalidQuery(_))));
+    }
+}
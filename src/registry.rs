@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::OnceLock;
+use crate::cards::{self, Card, Error, Project, Task, Status, Timelog, Book, Purchase, Metric, Word, Achievement, Note, Thought, Notebook};
+use crate::schemas::CardSchema;
+
+// The registry is the one place that knows which card types exist. Everything that has to
+// deal with all card types (creating tables, indexing, watching, routing) iterates over it
+// rather than naming types individually.
+//
+// Built-in types are listed in Registry::new(). Types defined by schema files are added
+// at startup.
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+// Type-erased view of a card type.
+pub trait CardKind: Send + Sync {
+
+    fn typ_str(&self) -> &str;
+    fn code(&self) -> u32;
+    fn sql_table(&self) -> &str;
+    fn is_builtin(&self) -> bool;
+
+    // Read card `id` from the file at `path` and write it to the card table, Links and Taggings.
+    fn sql_load(&self, db: &rusqlite::Connection, id: u64, path: &Path) -> Result<(), Error>;
+
+    // Check that the file at `path` holds a valid card of this type without touching the index.
+    fn validate(&self, id: u64, path: &Path) -> Result<(), Error>;
+
+    fn json(&self, db: &rusqlite::Connection, id: u64) -> Result<String, Error> {
+        cards::read_card_json(&cards::sql_find_card_file(db, self.typ_str(), self.code(), id)?)
+    }
+
+    fn sql_find_id(&self, db: &rusqlite::Connection, name_or_id: &str) -> Result<u64, Error> {
+        cards::sql_find_card_id(db, self.typ_str(), self.sql_table(), name_or_id)
+    }
+
+    fn sql_list_ids(&self, db: &rusqlite::Connection, query: &HashMap<String, String>, options: &cards::ListOptions) -> Result<Vec<u64>, Error> {
+        cards::sql_list_card_ids(db, self.code(), self.sql_table(), query, options)
+    }
+
+    fn sql_list_rows(&self, db: &rusqlite::Connection, query: &HashMap<String, String>, options: &cards::ListOptions) -> Result<Vec<serde_json::Value>, Error> {
+        cards::sql_list_card_rows(db, self.code(), self.sql_table(), query, options)
+    }
+}
+
+struct BuiltinKind<T: Card>(PhantomData<fn() -> T>);
+
+impl<T: Card> CardKind for BuiltinKind<T> {
+
+    fn typ_str(&self) -> &str { T::typ_str() }
+    fn code(&self) -> u32 { T::typ() as u32 }
+    fn sql_table(&self) -> &str { T::sql_table() }
+    fn is_builtin(&self) -> bool { true }
+
+    fn sql_load(&self, db: &rusqlite::Connection, id: u64, path: &Path) -> Result<(), Error> {
+        let (mut sql, mut link, mut tag_insert, mut tag_lookup, mut tagging_insert) = cards::prepare_card_write_stmts(db, T::sql_write_stmt())
+            .map_err(Error::DatabaseError)?;
+
+        let card = T::load(path, id)?;
+        card.sql_write(&mut sql)?;
+        card.sql_write_links(&mut link)?;
+        card.sql_write_tags(&mut tag_insert, &mut tag_lookup, &mut tagging_insert)
+    }
+
+    fn validate(&self, id: u64, path: &Path) -> Result<(), Error> {
+        let card = T::load(path, id)?;
+        cards::validate_card_links(card.links())
+    }
+}
+
+impl CardKind for CardSchema {
+
+    fn typ_str(&self) -> &str { CardSchema::typ_str(self) }
+    fn code(&self) -> u32 { CardSchema::code(self) }
+    fn sql_table(&self) -> &str { CardSchema::sql_table(self) }
+    fn is_builtin(&self) -> bool { false }
+
+    fn sql_load(&self, db: &rusqlite::Connection, id: u64, path: &Path) -> Result<(), Error> {
+        let (mut sql, mut link, mut tag_insert, mut tag_lookup, mut tagging_insert) = cards::prepare_card_write_stmts(db, &self.sql_write_stmt())
+            .map_err(Error::DatabaseError)?;
+
+        let card = self.load(path, id)?;
+        card.sql_write(&mut sql)?;
+        card.sql_write_links(&mut link)?;
+        card.sql_write_tags(&mut tag_insert, &mut tag_lookup, &mut tagging_insert)
+    }
+
+    fn validate(&self, id: u64, path: &Path) -> Result<(), Error> {
+        let card = self.load(path, id)?;
+        card.validate_links()
+    }
+}
+
+pub struct Registry {
+    builtins: Vec<Box<dyn CardKind>>,
+    schemas: Vec<CardSchema>,
+}
+
+impl Registry {
+
+    pub fn new(schemas: Vec<CardSchema>) -> Registry {
+        fn builtin<T: Card + 'static>() -> Box<dyn CardKind> {
+            Box::new(BuiltinKind::<T>(PhantomData))
+        }
+        Registry {
+            builtins: vec![
+                builtin::<Project>(),
+                builtin::<Task>(),
+                builtin::<Status>(),
+                builtin::<Timelog>(),
+                builtin::<Purchase>(),
+                builtin::<Metric>(),
+                builtin::<Word>(),
+                builtin::<Note>(),
+                builtin::<Thought>(),
+                builtin::<Achievement>(),
+                builtin::<Notebook>(),
+                builtin::<Book>(),
+            ],
+            schemas,
+        }
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = &dyn CardKind> {
+        self.builtins.iter()
+            .map(|kind| kind.as_ref())
+            .chain(self.schemas.iter().map(|schema| schema as &dyn CardKind))
+    }
+
+    pub fn schemas(&self) -> &[CardSchema] {
+        &self.schemas
+    }
+
+    pub fn find(&self, typ: &str) -> Option<&dyn CardKind> {
+        self.kinds().find(|kind| kind.typ_str() == typ)
+    }
+
+    pub fn find_by_code(&self, code: u32) -> Option<&dyn CardKind> {
+        self.kinds().find(|kind| kind.code() == code)
+    }
+
+    // The Cards view unions the fields every card has across all card tables. As the set
+    // of tables depends on the schema files, it is recreated on every start.
+    pub fn sql_create_cards_view(&self, db: &rusqlite::Connection) -> Result<(), Error> {
+        let selects = self.kinds()
+            .map(|kind| format!("SELECT {} AS card_type, '{}' AS type, id, title, created, modified, source FROM {}",
+                                kind.code(), kind.typ_str(), kind.sql_table()))
+            .collect::<Vec<String>>()
+            .join("\n            UNION ALL ");
+        db.execute_batch(&format!(r#"
+        DROP VIEW IF EXISTS Cards;
+        CREATE VIEW Cards AS
+            {};"#, selects))
+            .map_err(Error::DatabaseError)
+    }
+}
+
+pub fn init(registry: Registry) {
+    if REGISTRY.set(registry).is_err() {
+        panic!("Card type registry already initialized");
+    }
+}
+
+pub fn get() -> &'static Registry {
+    REGISTRY.get().expect("Card type registry not initialized")
+}
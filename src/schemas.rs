@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use rusqlite::params;
+use rusqlite::types::Value;
+use crate::cards::{self, CardData, CardType, Error};
+
+// Besides the card types implemented in cards.rs, card types can be declared through
+// schema files. Every <cards>/<type>.schema.json file defines one card type whose cards
+// live in <cards>/<type>/ like any other. For example, habit.schema.json:
+//
+//   {
+//     "Type": "habit",
+//     "Code": 100,
+//     "Table": "Habits",
+//     "Fields": [
+//       { "Property": "Started", "Column": "started", "Type": "DATETIME", "Required": true },
+//       { "Property": "Streak", "Type": "INTEGER" }
+//     ],
+//     "Indexes": [ ["started"] ]
+//   }
+//
+// - Type: name of the card type; used for the folder and in URLs and qualified IDs.
+// - Code: numeric type code stored in Links and Taggings. Must be unique and >= 100
+//   (codes below that are reserved for built-in types). Never change it once assigned.
+// - Table: optional; defaults to the capitalized type name plus "s".
+// - Fields: properties of the card beyond the common ones (Title, Created, Modified,
+//   Source, Tags, Links). Column defaults to the lowercased property name. Type is one of
+//   INTEGER, REAL, BOOLEAN, DATETIME, VARCHAR or TEXT. Required defaults to false.
+// - Indexes: optional; lists of columns to create indexes for.
+//
+// Schema files are read once on startup. When a schema file changes, the table for its
+// type is rebuilt from the card files.
+
+const SCHEMA_FILE_SUFFIX: &str = ".schema.json";
+const MIN_SCHEMA_TYPE_CODE: u32 = 100;
+// Type names that would be shadowed by other routes.
+const RESERVED_TYPES: &[&str] = &["cards", "import", "export", "search"];
+const RESERVED_TABLES: &[&str] = &[
+    "Tags", "Taggings", "Links", "CardFiles", "CardSchemas", "Cards", "CardText", "CardTextKeys", "IndexErrors", "schema_version",
+    "Projects", "Tasks", "Statuses", "Timelogs", "Books", "Purchases", "Metrics",
+    "Words", "Notes", "Thoughts", "Achievements", "Notebooks",
+];
+
+// Table and column names go into SQL unquoted so they can't be SQLite keywords
+// (https://www.sqlite.org/lang_keywords.html).
+const SQL_KEYWORDS: &[&str] = &[
+    "ABORT", "ACTION", "ADD", "AFTER", "ALL", "ALTER", "ALWAYS", "ANALYZE", "AND", "AS", "ASC",
+    "ATTACH", "AUTOINCREMENT", "BEFORE", "BEGIN", "BETWEEN", "BY", "CASCADE", "CASE", "CAST",
+    "CHECK", "COLLATE", "COLUMN", "COMMIT", "CONFLICT", "CONSTRAINT", "CREATE", "CROSS", "CURRENT",
+    "CURRENT_DATE", "CURRENT_TIME", "CURRENT_TIMESTAMP", "DATABASE", "DEFAULT", "DEFERRABLE",
+    "DEFERRED", "DELETE", "DESC", "DETACH", "DISTINCT", "DO", "DROP", "EACH", "ELSE", "END",
+    "ESCAPE", "EXCEPT", "EXCLUDE", "EXCLUSIVE", "EXISTS", "EXPLAIN", "FAIL", "FILTER", "FIRST",
+    "FOLLOWING", "FOR", "FOREIGN", "FROM", "FULL", "GENERATED", "GLOB", "GROUP", "GROUPS", "HAVING",
+    "IF", "IGNORE", "IMMEDIATE", "IN", "INDEX", "INDEXED", "INITIALLY", "INNER", "INSERT",
+    "INSTEAD", "INTERSECT", "INTO", "IS", "ISNULL", "JOIN", "KEY", "LAST", "LEFT", "LIKE", "LIMIT",
+    "MATCH", "MATERIALIZED", "NATURAL", "NO", "NOT", "NOTHING", "NOTNULL", "NULL", "NULLS", "OF",
+    "OFFSET", "ON", "OR", "ORDER", "OTHERS", "OUTER", "OVER", "PARTITION", "PLAN", "PRAGMA",
+    "PRECEDING", "PRIMARY", "QUERY", "RAISE", "RANGE", "RECURSIVE", "REFERENCES", "REGEXP",
+    "REINDEX", "RELEASE", "RENAME", "REPLACE", "RESTRICT", "RETURNING", "RIGHT", "ROLLBACK", "ROW",
+    "ROWS", "SAVEPOINT", "SELECT", "SET", "TABLE", "TEMP", "TEMPORARY", "THEN", "TIES", "TO",
+    "TRANSACTION", "TRIGGER", "UNBOUNDED", "UNION", "UNIQUE", "UPDATE", "USING", "VACUUM", "VALUES",
+    "VIEW", "VIRTUAL", "WHEN", "WHERE", "WINDOW", "WITH", "WITHOUT",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum FieldKind {
+    Integer,
+    Real,
+    Boolean,
+    Text,
+}
+
+struct FieldSchema {
+    property: String,
+    column: String,
+    sql_type: String,
+    kind: FieldKind,
+    required: bool,
+}
+
+pub struct CardSchema {
+    typ: String,
+    code: u32,
+    table: String,
+    fields: Vec<FieldSchema>,
+    indexes: Vec<Vec<String>>,
+    hash: i64,
+}
+
+pub struct SchemaCard {
+    code: u32,
+    data: CardData,
+    values: Vec<Value>,
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    let valid = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    };
+    valid && !SQL_KEYWORDS.iter().any(|keyword| keyword.eq_ignore_ascii_case(s))
+}
+
+// Type names must start with a letter. Paths starting with _ are reserved for the API.
+fn is_type_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_lowercase())
+        && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+impl CardSchema {
+
+    pub fn parse(contents: &str) -> Result<CardSchema, Error> {
+
+        fn invalid(msg: String) -> Error {
+            Error::InvalidSchema(msg)
+        }
+
+        let json: serde_json::Value = serde_json::from_str(contents)
+            .map_err(|err| invalid(format!("not valid JSON: {}", err)))?;
+
+        let typ = json["Type"].as_str()
+            .ok_or_else(|| invalid(String::from("missing Type")))?;
+        if !is_type_name(typ) {
+            return Err(invalid(format!("'{}' is not a valid type name (use a lowercase letter followed by lowercase letters, digits, _ and -)", typ)));
+        }
+        if RESERVED_TYPES.contains(&typ) {
+            return Err(invalid(format!("'{}' is a reserved name", typ)));
+        }
+        if typ == "invalid" || !matches!(CardType::from_str(typ), Ok(CardType::Invalid)) {
+            return Err(invalid(format!("'{}' is a built-in card type", typ)));
+        }
+
+        let code = json["Code"].as_u64()
+            .ok_or_else(|| invalid(format!("{}: missing numeric Code", typ)))?;
+        if code < MIN_SCHEMA_TYPE_CODE as u64 || code > u32::MAX as u64 {
+            return Err(invalid(format!("{}: Code must be at least {}", typ, MIN_SCHEMA_TYPE_CODE)));
+        }
+
+        let table = match json["Table"].as_str() {
+            Some(table) => String::from(table),
+            None => {
+                let mut chars = typ.chars();
+                let first = chars.next().unwrap().to_ascii_uppercase();
+                format!("{}{}s", first, chars.as_str()).replace('-', "_")
+            }
+        };
+        if !is_identifier(&table) {
+            return Err(invalid(format!("{}: '{}' is not a valid table name (letters, digits and _ but no SQL keywords)", typ, table)));
+        }
+        if RESERVED_TABLES.iter().any(|t| t.eq_ignore_ascii_case(&table)) {
+            return Err(invalid(format!("{}: table name '{}' is reserved", typ, table)));
+        }
+
+        let mut fields = Vec::new();
+        for field in json["Fields"].as_array().map(|a| a.as_slice()).unwrap_or(&[]) {
+            let property = field["Property"].as_str()
+                .ok_or_else(|| invalid(format!("{}: field is missing Property", typ)))?;
+            let column = field["Column"].as_str()
+                .map(String::from)
+                .unwrap_or_else(|| property.to_lowercase());
+            if !is_identifier(&column) {
+                return Err(invalid(format!("{}: '{}' is not a valid column name (letters, digits and _ but no SQL keywords)", typ, column)));
+            }
+            if ["id", "title", "created", "modified", "source"].contains(&column.to_lowercase().as_str())
+                || fields.iter().any(|f: &FieldSchema| f.column.eq_ignore_ascii_case(&column)) {
+                return Err(invalid(format!("{}: column '{}' is already taken", typ, column)));
+            }
+            let sql_type = field["Type"].as_str()
+                .ok_or_else(|| invalid(format!("{}: field {} is missing Type", typ, property)))?
+                .to_uppercase();
+            let kind = match sql_type.as_str() {
+                "INTEGER" => FieldKind::Integer,
+                "REAL" => FieldKind::Real,
+                "BOOLEAN" => FieldKind::Boolean,
+                "DATETIME" | "VARCHAR" | "TEXT" => FieldKind::Text,
+                _ => return Err(invalid(format!("{}: field {} has unsupported type {}", typ, property, sql_type))),
+            };
+            let required = field["Required"].as_bool().unwrap_or(false);
+            fields.push(FieldSchema {
+                property: String::from(property),
+                column,
+                sql_type,
+                kind,
+                required,
+            });
+        }
+
+        let mut indexes = Vec::new();
+        for index in json["Indexes"].as_array().map(|a| a.as_slice()).unwrap_or(&[]) {
+            let mut columns = Vec::new();
+            for column in index.as_array().map(|a| a.as_slice()).unwrap_or(&[]) {
+                let column = column.as_str()
+                    .ok_or_else(|| invalid(format!("{}: index columns must be strings", typ)))?;
+                if !["id", "title", "created", "modified", "source"].contains(&column)
+                    && !fields.iter().any(|f| f.column == column) {
+                    return Err(invalid(format!("{}: index on unknown column '{}'", typ, column)));
+                }
+                columns.push(String::from(column));
+            }
+            if columns.is_empty() {
+                return Err(invalid(format!("{}: index without columns", typ)));
+            }
+            indexes.push(columns);
+        }
+
+        Ok(CardSchema {
+            typ: String::from(typ),
+            code: code as u32,
+            table,
+            fields,
+            indexes,
+            hash: cards::hash_contents(contents.as_bytes()),
+        })
+    }
+
+    pub fn typ_str(&self) -> &str { &self.typ }
+    pub fn code(&self) -> u32 { self.code }
+    pub fn sql_table(&self) -> &str { &self.table }
+
+    pub fn sql_schema(&self) -> String {
+        let mut columns = String::new();
+        for field in self.fields.iter() {
+            columns.push_str(&format!(",\n            {} {}{}", field.column, field.sql_type, if field.required { " NOT NULL" } else { "" }));
+        }
+        let mut indexes = format!("\n        CREATE INDEX IF NOT EXISTS {}BySource ON {}(source);", self.table, self.table);
+        for (i, index) in self.indexes.iter().enumerate() {
+            indexes.push_str(&format!("\n        CREATE INDEX IF NOT EXISTS {}By{} ON {}({});", self.table, i, self.table, index.join(", ")));
+        }
+        format!(r#"
+        CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR NOT NULL,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            source VARCHAR{}
+        );{}"#, self.table, columns, indexes)
+    }
+
+    pub fn sql_write_stmt(&self) -> String {
+        let mut columns = String::from("id, title, created, modified, source");
+        let mut values = String::from("?1, ?2, ?3, ?4, ?5");
+        for (i, field) in self.fields.iter().enumerate() {
+            columns.push_str(&format!(", {}", field.column));
+            values.push_str(&format!(", ?{}", i + 6));
+        }
+        format!("INSERT OR REPLACE INTO {} ({}) VALUES({})", self.table, columns, values)
+    }
+
+    pub fn load(&self, path: &Path, id: u64) -> Result<SchemaCard, Error> {
+
+        fn read_value(json: &serde_json::Value, field: &FieldSchema) -> Result<Value, Error> {
+            let expected = match field.kind {
+                FieldKind::Integer => "an integer",
+                FieldKind::Real => "a number",
+                FieldKind::Boolean => "a boolean",
+                FieldKind::Text => "a string",
+            };
+            let err = || Error::CantReadProperty(cards::PropertyError::new(&field.property, expected, json.get(&field.property)));
+            let value = match json.get(&field.property) {
+                None | Some(serde_json::Value::Null) =>
+                    return if field.required { Err(err()) } else { Ok(Value::Null) },
+                Some(value) => value,
+            };
+            Ok(match (field.kind, value) {
+                (FieldKind::Integer, serde_json::Value::Number(n)) => Value::Integer(n.as_i64().ok_or_else(err)?),
+                (FieldKind::Integer, serde_json::Value::String(s)) => Value::Integer(s.parse::<i64>().map_err(|_| err())?),
+                (FieldKind::Real, serde_json::Value::Number(n)) => Value::Real(n.as_f64().ok_or_else(err)?),
+                (FieldKind::Real, serde_json::Value::String(s)) => Value::Real(s.parse::<f64>().map_err(|_| err())?),
+                (FieldKind::Boolean, serde_json::Value::Bool(b)) => Value::Integer(*b as i64),
+                (FieldKind::Boolean, serde_json::Value::String(s)) => Value::Integer(s.parse::<bool>().map_err(|_| err())? as i64),
+                (FieldKind::Text, serde_json::Value::String(s)) => Value::Text(s.clone()),
+                (FieldKind::Text, serde_json::Value::Number(n)) => Value::Text(n.to_string()),
+                (FieldKind::Text, serde_json::Value::Bool(b)) => Value::Text(b.to_string()),
+                _ => return Err(err()),
+            })
+        }
+
+        let data = cards::load_card_data(&self.typ, path, id)?;
+        let mut values = Vec::new();
+        for field in self.fields.iter() {
+            values.push(read_value(&data.contents, field).map_err(|e| e.in_card(&self.typ, id, path))?);
+        }
+
+        Ok(SchemaCard {
+            code: self.code,
+            data,
+            values,
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        cards::get_path_to_card_type(&self.typ)
+    }
+}
+
+impl SchemaCard {
+
+    pub fn sql_write(&self, stmt: &mut rusqlite::Statement) -> Result<usize, Error> {
+        let mut values = vec![
+            Value::Integer(self.data.id as i64),
+            Value::Text(self.data.title.clone()),
+            Value::Text(self.data.created.clone()),
+            Value::Text(self.data.modified.clone()),
+            self.data.source.clone().map(Value::Text).unwrap_or(Value::Null),
+        ];
+        values.extend(self.values.iter().cloned());
+        stmt.execute(rusqlite::params_from_iter(values.iter()))
+            .map_err(Error::DatabaseError)
+    }
+
+    pub fn validate_links(&self) -> Result<(), Error> {
+        cards::validate_card_links(self.data.links.iter())
+    }
+
+    pub fn sql_write_links(&self, db: &mut rusqlite::Statement) -> Result<(), Error> {
+        cards::sql_write_card_links(self.code, self.data.id, self.data.links.iter(), db)
+    }
+
+    pub fn sql_write_tags(&self, tag_insert: &mut rusqlite::Statement, tag_lookup: &mut rusqlite::Statement, tagging_insert: &mut rusqlite::Statement) -> Result<(), Error> {
+        cards::sql_write_card_tags(self.code, self.data.id, self.data.tags.iter(), tag_insert, tag_lookup, tagging_insert)
+    }
+}
+
+// Read all schema files from the card root.
+pub fn load_schemas() -> Result<Vec<CardSchema>, Error> {
+
+    let mut schemas: Vec<CardSchema> = Vec::new();
+
+    let root = cards::get_path_to_cards();
+    let entries = root.read_dir()
+        .map_err(|err| Error::InvalidSchema(format!("cannot read {}: {}", root.display(), err)))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_schema_file = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.ends_with(SCHEMA_FILE_SUFFIX))
+            .unwrap_or(false);
+        if !is_schema_file {
+            continue
+        }
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| Error::InvalidSchema(format!("cannot read {}: {}", path.display(), err)))?;
+        let schema = CardSchema::parse(&contents)
+            .map_err(|err| match err {
+                Error::InvalidSchema(msg) => Error::InvalidSchema(format!("{}: {}", path.display(), msg)),
+                err => err,
+            })?;
+
+        if let Some(other) = schemas.iter().find(|s| s.typ == schema.typ || s.code == schema.code || s.table.eq_ignore_ascii_case(&schema.table)) {
+            return Err(Error::InvalidSchema(format!("{}: type, code or table clashes with type '{}'", path.display(), other.typ)));
+        }
+
+        // Make sure there's something to list and watch.
+        fs::create_dir_all(schema.path())
+            .map_err(|err| Error::InvalidSchema(format!("cannot create {}: {}", schema.path().display(), err)))?;
+
+        schemas.push(schema);
+    }
+
+    Ok(schemas)
+}
+
+// Create tables for card types defined by schema files. Tables of types whose schema file
+// changed get rebuilt and tables of types whose schema file went away get dropped. In both
+// cases, the CardFiles bookkeeping is reset so that cards get re-read from disk.
+pub fn sync_schema_tables(db: &rusqlite::Connection, schemas: &[CardSchema]) -> Result<(), Error> {
+
+    let mut known = HashMap::new();
+    {
+        let mut stmt = db.prepare("SELECT type, code, tbl, hash FROM CardSchemas")
+            .map_err(Error::DatabaseError)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<usize, String>(0)?,
+                                                (row.get::<usize, u32>(1)?, row.get::<usize, String>(2)?, row.get::<usize, i64>(3)?))))
+            .map_err(Error::DatabaseError)?;
+        for row in rows {
+            let (typ, entry) = row.map_err(Error::DatabaseError)?;
+            known.insert(typ, entry);
+        }
+    }
+
+    fn drop_type(db: &rusqlite::Connection, code: u32, table: &str) -> rusqlite::Result<()> {
+        db.execute_batch(&format!("DROP TABLE IF EXISTS {};", table))?;
+        db.execute("DELETE FROM CardFiles WHERE card_type IS ?1", params![code])?;
+        db.execute("DELETE FROM Links WHERE from_type IS ?1", params![code])?;
+        db.execute("UPDATE Links SET dangling = 1 WHERE to_type IS ?1", params![code])?;
+        db.execute("DELETE FROM Taggings WHERE card_type IS ?1", params![code])?;
+        db.execute("DELETE FROM CardText WHERE rowid IN (SELECT rowid FROM CardTextKeys WHERE card_type IS ?1)", params![code])?;
+        db.execute("DELETE FROM CardTextKeys WHERE card_type IS ?1", params![code])?;
+        db.execute("DELETE FROM CardSchemas WHERE code IS ?1", params![code])?;
+        Ok(())
+    }
+
+    db.execute("BEGIN TRANSACTION", [])
+        .map_err(Error::DatabaseError)?;
+
+    let result = (|| -> rusqlite::Result<()> {
+        for schema in schemas {
+            match known.remove(&schema.typ) {
+                Some((_, _, hash)) if hash == schema.hash => continue,
+                Some((code, table, _)) => {
+                    println!("   Schema for {} changed; rebuilding {}", schema.typ, schema.table);
+                    drop_type(db, code, &table)?;
+                },
+                None => (),
+            }
+            db.execute_batch(&schema.sql_schema())?;
+            db.execute("INSERT OR REPLACE INTO CardSchemas (type, code, tbl, hash) VALUES(?1, ?2, ?3, ?4)",
+                       params![schema.typ, schema.code, schema.table, schema.hash])?;
+        }
+        for (typ, (code, table, _)) in known.iter() {
+            println!("   Schema for {} removed; dropping {}", typ, table);
+            drop_type(db, *code, table)?;
+        }
+        Ok(())
+    })();
+
+    if let Err(err) = result {
+        db.execute("ROLLBACK", [])
+            .map_err(Error::DatabaseError)?;
+        return Err(Error::DatabaseError(err));
+    }
+
+    db.execute("COMMIT", [])
+        .map_err(Error::DatabaseError)?;
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use rusqlite::{OptionalExtension, params};
+use crate::cards::Error;
+
+// Full-text search over cards of all types. Every card gets a row in the CardText FTS5 table
+// with its title, its tags and everything else in it that reads like text (all string
+// values other than the common fields, dates and links), so new card types are searchable
+// without having to say which of their fields hold text.
+//
+// FTS5 tables can only be looked up quickly by rowid. CardTextKeys hands out a rowid for
+// every card so that updating a card doesn't have to scan the whole text index.
+
+pub const MAX_SEARCH_RESULTS: u32 = 100;
+
+// Fields every card has that are either indexed separately or aren't text.
+const NON_TEXT_FIELDS: &[&str] = &["Id", "Type", "Title", "Tags", "Links", "Created", "Modified", "Source"];
+
+pub struct SearchHit {
+    pub card: (u32, u64),
+    pub title: String,
+    pub snippet: String,
+    pub score: f64,
+}
+
+fn is_date(value: &str) -> bool {
+    chrono::DateTime::parse_from_rfc3339(value).is_ok()
+        || chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+        || chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").is_ok()
+}
+
+fn collect_text(value: &serde_json::Value, text: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) if !s.is_empty() && !is_date(s) => text.push(s.clone()),
+        serde_json::Value::Array(values) => values.iter().for_each(|value| collect_text(value, text)),
+        serde_json::Value::Object(values) => values.values().for_each(|value| collect_text(value, text)),
+        _ => (),
+    }
+}
+
+pub fn sql_remove_card_text(db: &rusqlite::Connection, code: u32, id: u64) -> Result<(), Error> {
+    let rowid = db.query_row("SELECT rowid FROM CardTextKeys WHERE card_type IS ?1 AND card_id IS ?2", params![code, id],
+                             |row| row.get::<usize, i64>(0))
+        .optional()
+        .map_err(Error::DatabaseError)?;
+    if let Some(rowid) = rowid {
+        db.execute("DELETE FROM CardText WHERE rowid IS ?1", params![rowid])
+            .map_err(Error::DatabaseError)?;
+        db.execute("DELETE FROM CardTextKeys WHERE rowid IS ?1", params![rowid])
+            .map_err(Error::DatabaseError)?;
+    }
+    Ok(())
+}
+
+// Replace the text indexed for the given card with what is in `contents` (the card's JSON).
+pub fn sql_write_card_text(db: &rusqlite::Connection, code: u32, id: u64, contents: &serde_json::Value) -> Result<(), Error> {
+
+    sql_remove_card_text(db, code, id)?;
+
+    let title = contents["Title"].as_str().unwrap_or_default();
+    let tags = match &contents["Tags"] {
+        serde_json::Value::Array(tags) => tags.iter()
+            .filter_map(|tag| tag.as_str())
+            .collect::<Vec<&str>>()
+            .join(" "),
+        _ => String::new(),
+    };
+    let mut body = Vec::new();
+    if let serde_json::Value::Object(fields) = contents {
+        for (_, value) in fields.iter().filter(|(name, _)| !NON_TEXT_FIELDS.contains(&name.as_str())) {
+            collect_text(value, &mut body);
+        }
+    }
+
+    db.execute("INSERT INTO CardTextKeys (card_type, card_id) VALUES(?1, ?2)", params![code, id])
+        .map_err(Error::DatabaseError)?;
+    db.execute("INSERT INTO CardText (rowid, title, body, tags) VALUES(last_insert_rowid(), ?1, ?2, ?3)",
+               params![title, body.join("\n"), tags])
+        .map_err(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+// FTS5 marks matches in the snippet with the given (control) characters in the raw text of
+// the card, which has to be escaped before the matches can be marked up.
+fn snippet_html(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '\u{2}' => html.push_str("<mark>"),
+            '\u{3}' => html.push_str("</mark>"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+// Escape everything FTS5 would read as query syntax by quoting each word.
+fn quote_terms(q: &str) -> String {
+    q.split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Cards matching `q`, best matches first. `q` can use FTS5 query syntax (OR, NOT, "phrases",
+// prefix*, title:word, ...); if it doesn't parse as such, its words are searched for as they
+// are. Matches in titles count the most, then tags, then the rest. Snippets are HTML with
+// matches wrapped in <mark></mark>.
+pub fn sql_search(db: &rusqlite::Connection, q: &str, code: Option<u32>, limit: u32) -> Result<Vec<SearchHit>, Error> {
+
+    if q.trim().is_empty() {
+        return Err(Error::InvalidQuery(String::from("Search query is empty")));
+    }
+
+    let mut stmt = db.prepare_cached(r#"
+        SELECT CardTextKeys.card_type, CardTextKeys.card_id, CardText.title,
+               snippet(CardText, -1, char(2), char(3), '...', 16), bm25(CardText, 10.0, 1.0, 5.0) AS score
+        FROM CardText JOIN CardTextKeys ON CardTextKeys.rowid = CardText.rowid
+        WHERE CardText MATCH ?1 AND (?2 IS NULL OR CardTextKeys.card_type IS ?2)
+        ORDER BY score
+        LIMIT ?3"#)
+        .map_err(Error::DatabaseError)?;
+
+    let mut run = |q: &str| -> rusqlite::Result<Vec<SearchHit>> {
+        stmt.query_map(params![q, code, limit], |row| Ok(SearchHit {
+                card: (row.get(0)?, row.get(1)?),
+                title: row.get(2)?,
+                snippet: snippet_html(&row.get::<usize, String>(3)?),
+                // bm25() is lower for better matches.
+                score: -row.get::<usize, f64>(4)?,
+            }))?
+            .collect()
+    };
+
+    let hits = match run(q) {
+        Err(err) if is_query_error(&err) => run(&quote_terms(q)),
+        result => result,
+    };
+    hits.map_err(|err| match is_query_error(&err) {
+        true => Error::InvalidQuery(format!("Cannot search for '{}': {}", q, err)),
+        false => Error::DatabaseError(err),
+    })
+}
+
+// Whether FTS5 couldn't make sense of the query (as opposed to the search itself failing).
+fn is_query_error(err: &rusqlite::Error) -> bool {
+    match err {
+        rusqlite::Error::SqliteFailure(err, Some(msg)) if err.code == rusqlite::ErrorCode::Unknown =>
+            ["fts5: syntax error", "unterminated string", "no such column", "unknown special query"]
+                .iter().any(|prefix| msg.starts_with(prefix)),
+        _ => false,
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use notify::{RecursiveMode, Watcher};
+use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use crate::cards;
+use crate::registry::CardKind;
+use crate::report;
+
+// File system events don't go to the index directly. Watchers push them into a queue that
+// is drained by a single indexer thread. Events for the same card are debounced (the card
+// is only touched once no new events have come in for it for the configured window) and
+// coalesced into one net change. Everything that is ready at the same time is applied in
+// one transaction followed by a single report refresh (if any card actually changed).
+//
+// Renames are split into their two halves: the old name goes away, the new one appears.
+// That way editors that save through a temporary file, backup renames and moves between
+// type folders (which re-type the card) all come out as the right net change.
+//
+// Type folders are watched recursively as cards may be kept in sub-directories. When a whole
+// sub-directory appears or disappears we only get told about the directory, not the cards
+// in it, so the type folder gets rescanned instead.
+//
+// Events can also get lost altogether (watcher queue overflows, network drives, sync tools
+// replacing files behind our back). The reconciler therefore periodically queues a rescan
+// of every type folder. Rescans are incremental so they only cost a directory walk and a
+// stat() per card unless something has actually drifted.
+
+// Changes that settle within this long after the first one are applied in the same batch,
+// so that both halves of a move are seen together.
+const BATCH_SLACK: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Created,
+    Modified,
+    Removed,
+}
+
+pub struct WatchEvent {
+    kind: &'static dyn CardKind,
+    id: Option<u64>, // None for a sub-directory
+    path: PathBuf,
+    change: Change,
+}
+
+struct PendingChange {
+    kind: &'static dyn CardKind,
+    id: Option<u64>,
+    path: PathBuf,
+    change: Change,
+    deadline: Instant,
+}
+
+pub struct FileWatcher(notify::RecommendedWatcher);
+
+// Net effect of `previous` followed by `next`. A file that is created and removed again
+// still counts as removed as "created" may have replaced a card we already knew about.
+fn coalesce(previous: Change, next: Change) -> Change {
+    match (previous, next) {
+        (_, Change::Removed) => Change::Removed,
+        (Change::Created, _) => Change::Created,
+        (Change::Modified, _) => Change::Modified,
+        // Editors saving by deleting and recreating the file.
+        (Change::Removed, _) => Change::Modified,
+    }
+}
+
+pub fn spawn_indexer(db: Pool<SqliteConnectionManager>, report_thread: mpsc::Sender<report::ReportThreadCommand>, window: Duration) -> mpsc::Sender<WatchEvent> {
+
+    let (tx, rx) = mpsc::channel::<WatchEvent>();
+
+    thread::spawn(move || {
+
+        // Keyed by path rather than card ID as the two halves of a move within a type folder
+        // (e.g. into a sub-directory) are different changes to the same card.
+        let mut pending: HashMap<(u32, PathBuf), PendingChange> = HashMap::new();
+
+        loop {
+            let event = match pending.values().map(|p| p.deadline).min() {
+                Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+
+            let disconnected = match event {
+                Ok(event) => {
+                    let key = (event.kind.code(), event.path.clone());
+                    let deadline = Instant::now() + window;
+                    let change = match pending.get(&key) {
+                        Some(previous) => coalesce(previous.change, event.change),
+                        None => event.change,
+                    };
+                    pending.insert(key, PendingChange { kind: event.kind, id: event.id, path: event.path, change, deadline });
+                    false
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => false,
+                Err(mpsc::RecvTimeoutError::Disconnected) => true,
+            };
+
+            // Apply whatever has settled. Flush everything when the watchers are gone.
+            if !disconnected && pending.values().all(|p| p.deadline > Instant::now()) {
+                continue
+            }
+            let now = Instant::now() + BATCH_SLACK;
+            let ready: Vec<(u32, PathBuf)> = pending.iter()
+                .filter(|(_, p)| disconnected || p.deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            if !ready.is_empty() {
+                let batch: Vec<PendingChange> = ready.iter()
+                    .map(|key| pending.remove(key).unwrap())
+                    .collect();
+                let result = db.get()
+                    .map_err(|err| cards::Error::DatabaseUnavailable(format!("Cannot get DB connection: {}", err)))
+                    .and_then(|db| apply_changes(&db, &batch));
+                match result {
+                    Ok(true) => report::update_report(&report_thread),
+                    Ok(false) => (),
+                    // Nothing of the batch has been applied. Try it again once the window has
+                    // passed unless there's no one left to wait for.
+                    Err(e) if !disconnected => {
+                        println!("Cannot apply file changes, retrying: {}", e);
+                        let deadline = Instant::now() + window;
+                        for change in batch {
+                            pending.insert((change.kind.code(), change.path.clone()), PendingChange { deadline, ..change });
+                        }
+                    },
+                    Err(e) => println!("Cannot apply file changes: {}", e),
+                }
+            }
+
+            if disconnected {
+                break
+            }
+        }
+    });
+
+    tx
+}
+
+// Applies the whole batch or, if the index can't be written, none of it. Returns whether any
+// card was touched.
+fn apply_changes(db: &rusqlite::Connection, batch: &[PendingChange]) -> Result<bool, cards::Error> {
+    crate::in_transaction(db, || apply_changes_in_transaction(db, batch))
+}
+
+fn apply_changes_in_transaction(db: &rusqlite::Connection, batch: &[PendingChange]) -> Result<bool, cards::Error> {
+
+    let mut changed = false;
+
+    // A card that disappears from one place and appears in another within the same batch has
+    // been moved. Moving it to another type folder re-types it and links to it under its old
+    // type become dangling. Moving it within its type folder doesn't remove it at all.
+    let moved_to = |id: u64, path: &Path| batch.iter()
+        .find(|other| other.id == Some(id) && other.path != path && other.change != Change::Removed)
+        .map(|other| other.kind);
+    let moved_from = |code: u32, id: u64| batch.iter()
+        .any(|other| other.id == Some(id) && other.kind.code() != code && other.change == Change::Removed);
+
+    for pending in batch.iter() {
+        let kind = pending.kind;
+        let typ = kind.typ_str();
+        let id = match pending.id {
+            Some(id) => id,
+            None => continue,
+        };
+        match pending.change {
+            Change::Created | Change::Modified => {
+                // Cards written through the API are indexed already but the report still
+                // has to catch up with them.
+                if let Ok(true) = crate::is_card_file_indexed(kind, id, &pending.path, db) {
+                    changed = true;
+                    continue;
+                }
+                if !moved_from(kind.code(), id) {
+                    println!("{} card {}/{}", if pending.change == Change::Created { "Added" } else { "Modified" }, typ, id);
+                }
+                crate::remove_card_from_db(kind.sql_table(), kind.code(), id, db)?;
+                crate::quarantine::load_or_quarantine(kind, id, &pending.path, db)?;
+                changed = true;
+            },
+            Change::Removed => {
+                match moved_to(id, &pending.path) {
+                    Some(to) if to.code() == kind.code() => continue,
+                    Some(to) => println!("Moved card {}/{} to {}/{}", typ, id, to.typ_str(), id),
+                    None => println!("Removed card {}/{}", typ, id),
+                }
+                crate::remove_card_from_db(kind.sql_table(), kind.code(), id, db)?;
+                changed = true;
+            },
+        }
+    }
+
+    for pending in batch.iter().filter(|pending| pending.id.is_none()) {
+        let typ = pending.kind.typ_str();
+        // The index stays as it is until the folder can be read again (the next rescan).
+        let report = match crate::sync_cards_with_db(pending.kind, db) {
+            Ok(report) => report,
+            Err(e @ cards::Error::CantReadCardFolder(..)) => {
+                println!("Cannot rescan '{}/': {}", typ, e);
+                continue
+            },
+            Err(e) => return Err(e),
+        };
+        changed |= !report.is_empty();
+        for (what, ids) in [("added", &report.added), ("updated", &report.updated), ("removed", &report.removed), ("skipped", &report.skipped)] {
+            for id in ids.iter() {
+                println!("Rescan {} card {}/{}", what, typ, id);
+            }
+        }
+    }
+
+    Ok(changed)
+}
+
+pub fn init_watcher(kind: &'static dyn CardKind, queue: mpsc::Sender<WatchEvent>) -> FileWatcher {
+
+    let path = cards::get_path_to_card_type(kind.typ_str());
+
+    let mut watcher = notify::recommended_watcher(move |res: Result<notify::Event, notify::Error>| {
+
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                println!("FSWatcher error happened: {}", e.to_string());
+                return;
+            },
+        };
+
+        // The watcher has lost track of what happened.
+        if event.need_rescan() {
+            let _ = queue.send(WatchEvent { kind, id: None, path: cards::get_path_to_card_type(kind.typ_str()), change: Change::Modified });
+            return;
+        }
+
+        let changes: Vec<(Change, &Path)> = match event.kind {
+            notify::EventKind::Create(_) => event.paths.iter().map(|p| (Change::Created, p.as_path())).collect(),
+            notify::EventKind::Remove(_) => event.paths.iter().map(|p| (Change::Removed, p.as_path())).collect(),
+            notify::EventKind::Modify(ModifyKind::Name(mode)) => match mode {
+                RenameMode::From => event.paths.iter().map(|p| (Change::Removed, p.as_path())).collect(),
+                RenameMode::To => event.paths.iter().map(|p| (Change::Created, p.as_path())).collect(),
+                RenameMode::Both if event.paths.len() == 2 => vec![(Change::Removed, event.paths[0].as_path()),
+                                                                    (Change::Created, event.paths[1].as_path())],
+                // Don't know which end of the rename we're looking at.
+                _ => event.paths.iter()
+                    .map(|p| (if p.exists() { Change::Created } else { Change::Removed }, p.as_path()))
+                    .collect(),
+            },
+            // Usually arrives twice per save; the queue folds them together.
+            notify::EventKind::Modify(_) => event.paths.iter().map(|p| (Change::Modified, p.as_path())).collect(),
+            _ => Vec::new(), // Ignore
+        };
+
+        // Files that are gone can't tell us whether they were directories. Go by the
+        // event if it knows, otherwise assume anything without an extension was one.
+        let may_be_directory = !matches!(event.kind, notify::EventKind::Create(CreateKind::File) | notify::EventKind::Remove(RemoveKind::File));
+
+        for (change, path) in changes {
+            let id = match cards::get_card_id_from_path(path) {
+                Some(id) => Some(id),
+                None if change != Change::Modified && may_be_directory
+                    && (path.is_dir() || (!path.exists() && path.extension().is_none())) => None,
+                None => continue,
+            };
+            // Only fails if the indexer is gone in which case we're shutting down.
+            let _ = queue.send(WatchEvent { kind, id, path: path.to_path_buf(), change });
+        }
+    })
+        .expect("Cannot create file system watcher");
+
+    watcher.watch(path.as_path(), RecursiveMode::Recursive)
+        .expect("Cannot watch card directory");
+
+    FileWatcher(watcher)
+}
+
+pub fn spawn_reconciler(queue: mpsc::Sender<WatchEvent>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            for kind in crate::registry::get().kinds() {
+                let event = WatchEvent { kind, id: None, path: cards::get_path_to_card_type(kind.typ_str()), change: Change::Modified };
+                if queue.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coalesce_all(changes: &[Change]) -> Change {
+        changes[1..].iter().fold(changes[0], |previous, next| coalesce(previous, *next))
+    }
+
+    #[test]
+    fn removal_wins_over_earlier_changes() {
+        assert_eq!(coalesce(Change::Created, Change::Removed), Change::Removed);
+        assert_eq!(coalesce(Change::Modified, Change::Removed), Change::Removed);
+        assert_eq!(coalesce(Change::Removed, Change::Removed), Change::Removed);
+    }
+
+    #[test]
+    fn created_files_stay_created_until_removed() {
+        assert_eq!(coalesce(Change::Created, Change::Modified), Change::Created);
+        assert_eq!(coalesce_all(&[Change::Created, Change::Modified, Change::Modified]), Change::Created);
+        assert_eq!(coalesce(Change::Modified, Change::Created), Change::Modified);
+    }
+
+    #[test]
+    fn files_deleted_and_recreated_count_as_modified() {
+        assert_eq!(coalesce(Change::Removed, Change::Created), Change::Modified);
+        assert_eq!(coalesce_all(&[Change::Modified, Change::Removed, Change::Created, Change::Modified]), Change::Modified);
+        assert_eq!(coalesce_all(&[Change::Created, Change::Removed, Change::Created]), Change::Modified);
+    }
+}